@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::io;
+
+use ex::options::Options;
+use ex::parser::parse_transactions;
+
+fn generated_input(rows: u32) -> Vec<u8> {
+    let mut input = "type,client,tx,amount\n".to_string();
+    for tx in 1..=rows {
+        let client = tx % 1000;
+        let row = match tx % 5 {
+            0 => format!("widthdrawal,{},{},{}.25\n", client, tx, tx % 13),
+            3 => format!("dispute,{},{},\n", client, tx - 1),
+            4 => format!("resolve,{},{},\n", client, tx - 2),
+            _ => format!("deposit,{},{},{}.5\n", client, tx, tx % 17),
+        };
+        input.push_str(&row);
+    }
+    input.into_bytes()
+}
+
+fn bench_read_ahead(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let input = generated_input(200_000);
+
+    let mut group = c.benchmark_group("parse_transactions");
+    group.sample_size(10);
+    for read_ahead in [0, 2, 8] {
+        let options = Options {
+            read_ahead,
+            ..Default::default()
+        };
+        group.bench_function(format!("read_ahead_{}", read_ahead), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(parse_transactions(
+                        input.as_slice(),
+                        &options,
+                        &mut io::sink(),
+                    ))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_ahead);
+criterion_main!(benches);
@@ -0,0 +1,191 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+
+use crate::entities::client::Client;
+use crate::options::Options;
+use crate::parser::{ClientHash, ClientKey, Engine};
+
+/// A client whose balances differ between two outputs, missing clients having zero balances
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientDiff {
+    pub key: ClientKey,
+    pub before: Client,
+    pub after: Client,
+}
+
+impl ClientDiff {
+    /// Values of the report columns, deltas being `after - before`
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.key.0.to_string(),
+            Client::format_amount(self.after.available - self.before.available),
+            Client::format_amount(self.after.held - self.before.held),
+            Client::format_amount(self.after.total - self.before.total),
+            self.before.locked.to_string(),
+            self.after.locked.to_string(),
+        ]
+    }
+}
+
+/// Clients whose balances or lock differ between the outputs `before` and `after`, ordered by
+/// id and currency. Both are loaded as for `--initial-state`.
+pub fn diff_outputs<R: Read, S: Read>(before: R, after: S) -> anyhow::Result<Vec<ClientDiff>> {
+    let mut before_engine = Engine::new(Options::default());
+    before_engine.load_clients(before)?;
+    let mut after_engine = Engine::new(Options::default());
+    after_engine.load_clients(after)?;
+    let (before, after) = (before_engine.clients(), after_engine.clients());
+
+    let keys = before.keys().chain(after.keys()).collect::<BTreeSet<_>>();
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| {
+            let missing = || Client {
+                id: key.0,
+                currency: key.1.clone(),
+                ..Default::default()
+            };
+            let diff = ClientDiff {
+                key: key.clone(),
+                before: before.get(key).cloned().unwrap_or_else(missing),
+                after: after.get(key).cloned().unwrap_or_else(missing),
+            };
+            (!same_balances(&diff.before, &diff.after)).then_some(diff)
+        })
+        .collect())
+}
+
+/// Keeps the `clients` whose balances or lock differ from those of the `baseline` output,
+/// loaded as for `--initial-state`. Clients missing from it have zero balances.
+pub fn retain_changed<R: Read>(clients: &mut ClientHash, baseline: R) -> anyhow::Result<()> {
+    let mut baseline_engine = Engine::new(Options::default());
+    baseline_engine.load_clients(baseline)?;
+    let baseline = baseline_engine.clients();
+    clients.retain(|key, client| {
+        let before = baseline.get(key).cloned().unwrap_or_else(|| Client {
+            id: key.0,
+            currency: key.1.clone(),
+            ..Default::default()
+        });
+        !same_balances(&before, client)
+    });
+    Ok(())
+}
+
+fn same_balances(a: &Client, b: &Client) -> bool {
+    (a.available, a.held, a.locked) == (b.available, b.held, b.locked)
+}
+
+/// Writes `diffs` as CSV into `output`: the client, its available, held and total deltas and
+/// its lock on both sides
+pub fn write_diff<W: Write>(diffs: &[ClientDiff], output: W) -> anyhow::Result<()> {
+    let with_currency = diffs.iter().any(|diff| diff.key.1.is_some());
+    let mut headers = vec![
+        "client",
+        "available_delta",
+        "held_delta",
+        "total_delta",
+        "locked_before",
+        "locked_after",
+    ];
+    if with_currency {
+        headers.push("currency");
+    }
+
+    let mut wtr = csv::Writer::from_writer(output);
+    wtr.write_record(headers)?;
+    for diff in diffs {
+        let mut record = diff.fields();
+        if with_currency {
+            record.push(diff.key.1.clone().unwrap_or_default());
+        }
+        wtr.write_record(record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_transactions;
+    use assertor::*;
+
+    #[test]
+    fn test_diff_outputs() -> anyhow::Result<()> {
+        let before: &[u8] = b"client,available,held,total,locked
+1,1.5,0,1.5,false
+2,0,0,0,true
+3,4,1,5,false
+";
+        let after: &[u8] = b"client,available,held,total,locked
+3,4.25,1,5.25,false
+1,1.50,0,1.5,false
+2,0,0,0,true
+";
+        let diffs = diff_outputs(before, after)?;
+        assert_that!(diffs).has_length(1);
+
+        let mut output = vec![];
+        write_diff(&diffs, &mut output)?;
+        assert_that!(String::from_utf8(output)?.as_str()).is_equal_to(
+            "client,available_delta,held_delta,total_delta,locked_before,locked_after
+3,0.25,0,0.25,false,false
+",
+        );
+
+        assert_that!(diff_outputs(before, before)?).is_equal_to(vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retain_changed() -> anyhow::Result<()> {
+        let baseline = "client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2,0,2,false
+3,3,0,3,false
+";
+        let path = std::env::temp_dir().join(format!("ex-baseline-{}.csv", std::process::id()));
+        std::fs::write(&path, baseline)?;
+        let options = Options {
+            initial_state: Some(path.clone()),
+            ..Default::default()
+        };
+        // Client 2 is touched but ends where it started
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,1.0
+widthdrawal,2,3,1.0
+deposit,4,4,4.0
+";
+        let clients = parse_transactions(input, &options, &mut std::io::sink()).await;
+        std::fs::remove_file(&path)?;
+        let mut clients = clients?;
+        assert_that!(clients).has_length(4);
+
+        retain_changed(&mut clients, baseline.as_bytes())?;
+        let mut ids = clients.keys().map(|key| key.0 .0).collect::<Vec<_>>();
+        ids.sort();
+        assert_that!(ids).is_equal_to(vec![1, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_missing_client() -> anyhow::Result<()> {
+        let before: &[u8] = b"client,available,held,total,locked
+1,1.5,0,1.5,false
+";
+        let after: &[u8] = b"client,available,held,total,locked
+";
+        let diffs = diff_outputs(before, after)?;
+        assert_that!(diffs
+            .iter()
+            .map(|diff| diff.after.total)
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![rust_decimal::Decimal::ZERO]);
+
+        Ok(())
+    }
+}
@@ -1,32 +1,210 @@
 use csv::ByteRecord;
 use rust_decimal::Decimal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+use crate::entities::error::OutputError;
+use crate::entities::id::ClientId;
 
 /// Holds details for a given client
-#[derive(Default, Serialize, Debug)]
+///
+/// Equality compares `Decimal` values, so `7.890` and `7.89` are equal regardless of scale
+#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Client {
-    pub id: u16,
+    pub id: ClientId,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    pub currency: Option<String>,
+    /// Sum of all deposits, chargebacks aside
+    pub gross_deposits: Decimal,
+    /// Sum of all successful withdrawals
+    pub gross_withdrawals: Decimal,
+    /// Whether any of the client's transactions went through
+    #[serde(skip)]
+    pub active: bool,
 }
 
 impl Client {
     pub fn headers() -> Vec<&'static str> {
         vec!["client", "available", "held", "total", "locked"]
     }
+
+    /// Extra columns written with `--gross-totals`
+    pub fn gross_headers() -> Vec<&'static str> {
+        vec!["gross_deposits", "gross_withdrawals", "net_deposits"]
+    }
+
+    /// Values of the [`Client::gross_headers`] columns
+    pub fn gross_fields(&self) -> Vec<String> {
+        vec![
+            Client::format_amount(self.gross_deposits),
+            Client::format_amount(self.gross_withdrawals),
+            Client::format_amount(self.gross_deposits.saturating_sub(self.gross_withdrawals)),
+        ]
+    }
+
+    /// Extra columns written with `--cents-columns`
+    pub fn cents_headers() -> Vec<&'static str> {
+        vec!["available_cents", "held_cents", "total_cents"]
+    }
+
+    /// Values of the [`Client::cents_headers`] columns, rounded to whole cents. Amounts too large
+    /// to be counted in cents, near `Decimal::MAX`, are an error.
+    pub fn cents_fields(&self) -> Result<Vec<String>, OutputError> {
+        [self.available, self.held, self.total]
+            .iter()
+            .map(|amount| {
+                amount
+                    .checked_mul(Decimal::ONE_HUNDRED)
+                    .map(|cents| cents.round().to_string())
+                    .ok_or(OutputError::CentsOverflow {
+                        client: self.id,
+                        amount: *amount,
+                    })
+            })
+            .collect()
+    }
+
+    /// Copy of the client with its amounts rounded to `scale` decimal places, midpoints going
+    /// to the even neighbour
+    pub fn rounded(&self, scale: u32) -> Client {
+        Client {
+            available: self.available.round_dp(scale),
+            held: self.held.round_dp(scale),
+            total: self.total.round_dp(scale),
+            gross_deposits: self.gross_deposits.round_dp(scale),
+            gross_withdrawals: self.gross_withdrawals.round_dp(scale),
+            ..self.clone()
+        }
+    }
+
+    /// Canonical output of an amount: trailing zeros are stripped whatever scale the input
+    /// amounts had, e.g. `7.890` is written `7.89` and `2.00` is written `2`
+    pub fn format_amount(amount: Decimal) -> String {
+        amount.normalize().to_string()
+    }
+}
+
+/// Human-friendly form for logs, e.g. `client 1: available=1.5 held=0 total=1.5 locked=false`
+impl Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {}: available={} held={} total={} locked={}",
+            self.id, self.available, self.held, self.total, self.locked
+        )
+    }
 }
 
 /// Converts into a CSV record
 impl From<Client> for csv::ByteRecord {
     fn from(client: Client) -> Self {
+        ByteRecord::from(&client)
+    }
+}
+
+/// Converts into a CSV record, leaving the client in place
+impl From<&Client> for csv::ByteRecord {
+    fn from(client: &Client) -> Self {
         ByteRecord::from(vec![
             client.id.to_string(),
-            client.available.to_string(),
-            client.held.to_string(),
-            client.total.to_string(),
+            Client::format_amount(client.available),
+            Client::format_amount(client.held),
+            Client::format_amount(client.total),
             client.locked.to_string(),
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_cents_fields() {
+        let client = Client {
+            available: dec!(10.5),
+            held: dec!(0.004),
+            total: dec!(10.504),
+            ..Default::default()
+        };
+        assert_that!(client.cents_fields()).is_equal_to(Ok(vec![
+            "1050".to_string(),
+            "0".to_string(),
+            "1050".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn test_cents_fields_near_max() {
+        let client = Client {
+            id: ClientId(4),
+            available: Decimal::MAX,
+            total: Decimal::MAX,
+            ..Default::default()
+        };
+        assert_that!(client.cents_fields()).is_equal_to(Err(OutputError::CentsOverflow {
+            client: ClientId(4),
+            amount: Decimal::MAX,
+        }));
+
+        // The largest amounts which still fit
+        let amount = Decimal::MAX / Decimal::ONE_HUNDRED;
+        let client = Client {
+            available: amount,
+            total: amount,
+            ..Default::default()
+        };
+        assert!(client.cents_fields().is_ok());
+    }
+
+    #[test]
+    fn test_record_from_borrowed_client() {
+        let client = Client {
+            id: ClientId(3),
+            available: dec!(1.5),
+            held: dec!(0.25),
+            total: dec!(1.75),
+            locked: true,
+            ..Default::default()
+        };
+
+        let record = ByteRecord::from(&client);
+        assert_that!(record.clone())
+            .is_equal_to(ByteRecord::from(vec!["3", "1.5", "0.25", "1.75", "true"]));
+        assert_that!(record).is_equal_to(ByteRecord::from(client));
+    }
+
+    #[test]
+    fn test_display() {
+        let client = Client {
+            id: ClientId(7),
+            available: dec!(1.5),
+            held: dec!(0.25),
+            total: dec!(1.75),
+            locked: true,
+            ..Default::default()
+        };
+        assert_that!(client.to_string())
+            .is_equal_to("client 7: available=1.5 held=0.25 total=1.75 locked=true".to_string());
+    }
+
+    #[test]
+    fn test_format_amount() {
+        for (amount, formatted) in [
+            (dec!(7.890), "7.89"),
+            (dec!(2.00), "2"),
+            (dec!(0.0), "0"),
+            (dec!(-0.00), "0"),
+            (dec!(100), "100"),
+            (dec!(-3.1000), "-3.1"),
+            (dec!(0.0001), "0.0001"),
+        ] {
+            assert_that!(Client::format_amount(amount)).is_equal_to(formatted.to_string());
+        }
+    }
+}
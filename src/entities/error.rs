@@ -0,0 +1,63 @@
+use rust_decimal::Decimal;
+
+use crate::entities::id::{ClientId, TxId};
+
+/// Errors raised while applying a transaction
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TransactionError {
+    #[error("no amount for tx {0}")]
+    MissingAmount(TxId),
+    #[error("amount of tx {0} isn't a whole number of cents")]
+    FractionalCents(TxId),
+    #[error("non-existing disputed transaction {0}")]
+    NotDisputed(TxId),
+    #[error("tx {tx} belongs to client {owner}")]
+    ClientMismatch { tx: TxId, owner: ClientId },
+    #[error("amount {amount} doesn't match the disputed amount {disputed} of tx {tx}")]
+    AmountMismatch {
+        tx: TxId,
+        amount: Decimal,
+        disputed: Decimal,
+    },
+}
+
+/// Structural problems of a transaction, found before applying it
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("no amount for tx {0}")]
+    MissingAmount(TxId),
+    #[error("amount {amount} of tx {tx} isn't positive")]
+    NonPositiveAmount { tx: TxId, amount: Decimal },
+    #[error("tx {0} refers to another transaction and can't have an amount")]
+    UnexpectedAmount(TxId),
+    #[error("amount of tx {tx} has {places} decimal places, more than allowed")]
+    TooManyDecimals { tx: TxId, places: u32 },
+    #[error("amount {amount} of tx {tx} is over the {max} cap")]
+    AmountOverMax {
+        tx: TxId,
+        amount: Decimal,
+        max: Decimal,
+    },
+    #[error("client {client} of tx {tx} is over {max}, are the client and tx columns swapped?")]
+    ClientOverMax {
+        tx: TxId,
+        client: ClientId,
+        max: u16,
+    },
+}
+
+/// Rows which don't fit the input schema, skipped as they're read
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum SchemaError {
+    #[error("amount required for {r#type} at row {row}")]
+    AmountRequired { r#type: String, row: usize },
+    #[error("unknown transaction type {r#type} at row {row}")]
+    UnknownType { r#type: String, row: usize },
+}
+
+/// Clients which can't be written out
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum OutputError {
+    #[error("amount {amount} of client {client} is too large to be written in cents")]
+    CentsOverflow { client: ClientId, amount: Decimal },
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::entities::client::Client;
+use crate::entities::transaction::{Outcome, Transaction};
+
+/// Behavior of a custom transaction type, e.g. a fee or interest, registered in [`Handlers`]
+/// under the name of its type. Custom transactions aren't kept in the history, they can't be
+/// disputed.
+pub trait TransactionHandler: Send + Sync {
+    /// Applies `transaction` to its client, whose changes are only kept when applied
+    fn apply(&self, transaction: &Transaction, client: &mut Client) -> Outcome;
+}
+
+/// Handlers of the custom transaction types, by type name. Rows of an unregistered type are
+/// skipped as invalid.
+#[derive(Default, Clone)]
+pub struct Handlers(HashMap<String, Arc<dyn TransactionHandler>>);
+
+impl Handlers {
+    /// Handles the transactions of type `name` with `handler`, replacing any previous one
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        handler: impl TransactionHandler + 'static,
+    ) -> &mut Self {
+        self.0.insert(name.into(), Arc::new(handler));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn TransactionHandler> {
+        self.0.get(name).map(|handler| handler.as_ref())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+}
+
+impl fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::id::ClientId;
+    use crate::entities::transaction::Reason;
+    use crate::options::Options;
+    use crate::parser::parse_transactions;
+    use assertor::*;
+    use rust_decimal_macros::dec;
+    use std::io;
+
+    /// Debits the client, which may not go below zero
+    struct Fee;
+
+    impl TransactionHandler for Fee {
+        fn apply(&self, transaction: &Transaction, client: &mut Client) -> Outcome {
+            let amount = transaction.amount.unwrap_or_default();
+            if client.available < amount {
+                return Outcome::Rejected(Reason::InsufficientFunds);
+            }
+            client.available -= amount;
+            client.total -= amount;
+            Outcome::Applied
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_fee() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+fee,1,2,1.5
+fee,1,3,20.0
+interest,1,4,1.0
+deposit,2,5,1.0
+fee,2,6,0.5
+";
+        let mut options = Options::default();
+        options.handlers.register("fee", Fee);
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(8.5));
+        assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(8.5));
+        assert_that!(clients[&(ClientId(2), None)].total).is_equal_to(dec!(0.5));
+
+        // Unregistered, fees are skipped like any unknown type
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(10.0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// Identifies a client, read and written as a plain integer
+///
+/// Client and transaction ids can't be swapped by mistake:
+///
+/// ```compile_fail
+/// use ex::entities::id::{ClientId, TxId};
+///
+/// fn dispute(client: ClientId, tx: TxId) {}
+/// dispute(TxId(1), ClientId(2));
+/// ```
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct ClientId(pub u16);
+
+/// Identifies a transaction, read and written as a plain integer
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct TxId(pub u32);
+
+impl Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Display for TxId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
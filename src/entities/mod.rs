@@ -1,2 +1,5 @@
 pub mod client;
+pub mod error;
+pub mod handler;
+pub mod id;
 pub mod transaction;
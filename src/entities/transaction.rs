@@ -1,33 +1,335 @@
 use rust_decimal::Decimal;
-use serde::Deserialize;
-use std::fmt::Display;
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::entities::error::{SchemaError, TransactionError, ValidationError};
+use crate::entities::handler::Handlers;
+use crate::entities::id::{ClientId, TxId};
 
 /// All available types
-#[derive(Debug, Deserialize, Eq, PartialEq, Clone, strum_macros::Display)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
 pub enum TransactionType {
+    #[default]
     Deposit,
     Widthdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// Any other type, applied by the handler registered under its name in
+    /// `Options::handlers`
+    Custom(String),
 }
 
-impl Default for TransactionType {
-    fn default() -> Self {
-        TransactionType::Deposit
+impl TransactionType {
+    /// Spelling of the type in the input
+    pub fn name(&self) -> &str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Widthdrawal => "widthdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Custom(name) => name,
+        }
+    }
+
+    /// Type spelled exactly `name`
+    fn from_name(name: &str) -> Option<TransactionType> {
+        let r#type = match name {
+            "deposit" => TransactionType::Deposit,
+            "widthdrawal" => TransactionType::Widthdrawal,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::Chargeback,
+            _ => return None,
+        };
+        Some(r#type)
+    }
+
+    /// `name` as pasted from a document or spreadsheet, cleaned up: trimmed of any Unicode
+    /// whitespace such as non-breaking spaces, NFC normalized, and stripped of the combining
+    /// characters left over
+    pub fn clean(name: &str) -> String {
+        name.trim()
+            .nfc()
+            .filter(|c| !is_combining_mark(*c))
+            .collect()
+    }
+
+    /// Type spelled `name` in any case, in the plural or with an alternate spelling, see
+    /// `--normalize-types`
+    pub fn from_alias(name: &str) -> Option<TransactionType> {
+        let r#type = match TransactionType::clean(name).to_ascii_lowercase().as_str() {
+            "deposit" | "deposits" => TransactionType::Deposit,
+            "widthdrawal" | "widthdrawals" | "withdrawal" | "withdrawals" | "withdraw" => {
+                TransactionType::Widthdrawal
+            }
+            "dispute" | "disputes" => TransactionType::Dispute,
+            "resolve" | "resolves" | "resolved" => TransactionType::Resolve,
+            "chargeback" | "chargebacks" | "charge-back" | "charge_back" => {
+                TransactionType::Chargeback
+            }
+            _ => return None,
+        };
+        Some(r#type)
+    }
+}
+
+impl Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionType::Deposit => write!(f, "Deposit"),
+            TransactionType::Widthdrawal => write!(f, "Widthdrawal"),
+            TransactionType::Dispute => write!(f, "Dispute"),
+            TransactionType::Resolve => write!(f, "Resolve"),
+            TransactionType::Chargeback => write!(f, "Chargeback"),
+            TransactionType::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// Deserializes the canonical spellings, cleaned up with `TransactionType::clean` when they
+/// don't match as is, other names being custom types. Alternate spellings are left to
+/// `--normalize-types`.
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct TypeVisitor;
+
+        impl<'de> Visitor<'de> for TypeVisitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a transaction type")
+            }
+
+            fn visit_str<E: Error>(self, name: &str) -> Result<TransactionType, E> {
+                if let Some(r#type) = TransactionType::from_name(name) {
+                    return Ok(r#type);
+                }
+                let name = TransactionType::clean(name);
+                Ok(TransactionType::from_name(&name).unwrap_or(TransactionType::Custom(name)))
+            }
+        }
+
+        deserializer.deserialize_str(TypeVisitor)
+    }
+}
+
+/// Why a transaction wasn't applied
+#[derive(Debug, Serialize, Eq, PartialEq, Clone, Copy, strum_macros::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Reason {
+    InsufficientFunds,
+    UnknownTx,
+    NotDeposit,
+    WithdrawalNotDisputable,
+    NotDisputed,
+    ClientMismatch,
+    InvalidRow,
+    OutOfOrder,
+    Locked,
+    Overflow,
+    Duplicate,
+    AmountMismatch,
+}
+
+/// What applying a transaction resulted in
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Outcome {
+    Applied,
+    Rejected(Reason),
+}
+
+impl Outcome {
+    pub fn is_applied(&self) -> bool {
+        *self == Outcome::Applied
+    }
+
+    /// Why the transaction was rejected, if it was
+    pub fn reason(&self) -> Option<Reason> {
+        match self {
+            Outcome::Applied => None,
+            Outcome::Rejected(reason) => Some(*reason),
+        }
+    }
+}
+
+impl From<&TransactionError> for Reason {
+    fn from(error: &TransactionError) -> Self {
+        match error {
+            TransactionError::MissingAmount(_) | TransactionError::FractionalCents(_) => {
+                Reason::InvalidRow
+            }
+            TransactionError::NotDisputed(_) => Reason::NotDisputed,
+            TransactionError::ClientMismatch { .. } => Reason::ClientMismatch,
+            TransactionError::AmountMismatch { .. } => Reason::AmountMismatch,
+        }
+    }
+}
+
+impl From<&ValidationError> for Reason {
+    fn from(_: &ValidationError) -> Self {
+        Reason::InvalidRow
     }
 }
 
 /// Holds a single transaction
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Transaction {
     pub r#type: TransactionType,
-    pub client: u16,
-    pub tx: u32,
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(default, deserialize_with = "deserialize_amount")]
     pub amount: Option<Decimal>,
-    #[serde(skip)]
-    pub succeeded: bool,
+    /// Optional currency code, balances are tracked per (client, currency)
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Optional unix timestamp in seconds, see `--require-monotonic`
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// Parses the amount from its textual representation so no precision is lost, amounts which
+/// don't fit in a `Decimal` are rejected. An explicit sign is accepted, `+10.00` is `10.00`.
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .filter(|amount| !amount.is_empty())
+        .map(|amount| {
+            Decimal::from_str(&amount)
+                .map_err(|e| D::Error::custom(format!("invalid amount {}: {}", amount, e)))
+        })
+        .transpose()
+}
+
+impl Transaction {
+    /// Columns known in the input, `currency` and `timestamp` being optional
+    pub fn headers() -> Vec<&'static str> {
+        vec!["type", "client", "tx", "amount", "currency", "timestamp"]
+    }
+
+    /// Checks the row read at `row` fits the input schema: the amount column, optional, can
+    /// only be empty for disputes, resolves, chargebacks and custom types, which must have a
+    /// handler in `handlers`
+    pub fn check_schema(&self, row: usize, handlers: &Handlers) -> Result<(), SchemaError> {
+        match (&self.r#type, self.amount) {
+            (TransactionType::Deposit | TransactionType::Widthdrawal, None) => {
+                Err(SchemaError::AmountRequired {
+                    r#type: self.r#type.name().to_string(),
+                    row,
+                })
+            }
+            (TransactionType::Custom(name), _) if !handlers.contains(name) => {
+                Err(SchemaError::UnknownType {
+                    r#type: name.clone(),
+                    row,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the transaction is well-formed before applying it: deposits and withdrawals carry
+    /// a positive amount, disputes none. Resolves and chargebacks may repeat the disputed
+    /// amount, checked as they're applied. Custom types are up to their handler.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        match (&self.r#type, self.amount) {
+            (TransactionType::Deposit | TransactionType::Widthdrawal, None) => {
+                Err(ValidationError::MissingAmount(self.tx))
+            }
+            (TransactionType::Deposit | TransactionType::Widthdrawal, Some(amount))
+                if amount <= Decimal::ZERO =>
+            {
+                Err(ValidationError::NonPositiveAmount {
+                    tx: self.tx,
+                    amount,
+                })
+            }
+            (TransactionType::Deposit | TransactionType::Widthdrawal, Some(_)) => Ok(()),
+            (TransactionType::Custom(_), _) => Ok(()),
+            (TransactionType::Resolve | TransactionType::Chargeback, Some(_)) => Ok(()),
+            (_, Some(_)) => Err(ValidationError::UnexpectedAmount(self.tx)),
+            (_, None) => Ok(()),
+        }
+    }
+
+    /// Checks the amount has at most `max` decimal places, trailing zeros aside
+    pub fn check_decimals(&self, max: u32) -> Result<(), ValidationError> {
+        match self.amount.map(|amount| amount.normalize().scale()) {
+            Some(places) if places > max => Err(ValidationError::TooManyDecimals {
+                tx: self.tx,
+                places,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the amount, if any, is at most `max`
+    pub fn check_max_amount(&self, max: Decimal) -> Result<(), ValidationError> {
+        match self.amount {
+            Some(amount) if amount > max => Err(ValidationError::AmountOverMax {
+                tx: self.tx,
+                amount,
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the client id is at most `max`. Client ids span a much smaller range than tx ids,
+    /// one over it most likely means the two columns were swapped.
+    pub fn check_client_max(&self, max: u16) -> Result<(), ValidationError> {
+        if self.client.0 > max {
+            return Err(ValidationError::ClientOverMax {
+                tx: self.tx,
+                client: self.client,
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Copy of this transaction with its amount rounded to `places` decimal places, halves to
+    /// even
+    pub fn rounded(&self, places: u32) -> Transaction {
+        Transaction {
+            amount: self.amount.map(|amount| amount.round_dp(places)),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the amount, or an error if this transaction doesn't carry one
+    pub fn amount_or_error(&self) -> Result<Decimal, TransactionError> {
+        self.amount.ok_or(TransactionError::MissingAmount(self.tx))
+    }
+
+    /// Converts an amount read as an integer of cents into its decimal value
+    pub fn amount_from_cents(&mut self) -> Result<(), TransactionError> {
+        if let Some(amount) = self.amount {
+            let cents = amount.normalize();
+            if cents.scale() > 0 {
+                return Err(TransactionError::FractionalCents(self.tx));
+            }
+            self.amount = Some(Decimal::from_i128_with_scale(cents.mantissa(), 2));
+        }
+        Ok(())
+    }
 }
 
 /// For debug purpose
@@ -43,3 +345,328 @@ impl Display for Transaction {
         )
     }
 }
+
+/// Builds transactions in tests, e.g.
+/// `TransactionBuilder::resolve(1, 2).amount(dec!(5)).currency("USD").build()`
+#[cfg(test)]
+pub(crate) struct TransactionBuilder {
+    transaction: Transaction,
+}
+
+#[cfg(test)]
+impl TransactionBuilder {
+    pub fn new(r#type: TransactionType, client: u16, tx: u32) -> Self {
+        TransactionBuilder {
+            transaction: Transaction {
+                r#type,
+                client: ClientId(client),
+                tx: TxId(tx),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn deposit(client: u16, tx: u32, amount: Decimal) -> Self {
+        Self::new(TransactionType::Deposit, client, tx).amount(amount)
+    }
+
+    pub fn withdrawal(client: u16, tx: u32, amount: Decimal) -> Self {
+        Self::new(TransactionType::Widthdrawal, client, tx).amount(amount)
+    }
+
+    pub fn dispute(client: u16, tx: u32) -> Self {
+        Self::new(TransactionType::Dispute, client, tx)
+    }
+
+    pub fn resolve(client: u16, tx: u32) -> Self {
+        Self::new(TransactionType::Resolve, client, tx)
+    }
+
+    pub fn chargeback(client: u16, tx: u32) -> Self {
+        Self::new(TransactionType::Chargeback, client, tx)
+    }
+
+    pub fn amount(mut self, amount: Decimal) -> Self {
+        self.transaction.amount = Some(amount);
+        self
+    }
+
+    pub fn currency(mut self, currency: &str) -> Self {
+        self.transaction.currency = Some(currency.to_string());
+        self
+    }
+
+    pub fn build(self) -> Transaction {
+        self.transaction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use csv::ByteRecord;
+    use rust_decimal_macros::dec;
+
+    fn deserialize(amount: &str) -> csv::Result<Transaction> {
+        ByteRecord::from(vec!["deposit", "1", "1", amount]).deserialize(None)
+    }
+
+    #[test]
+    fn test_amount_keeps_precision() -> anyhow::Result<()> {
+        let transaction = deserialize("7922816251426433759354395033.5")?;
+        assert_that!(transaction.amount)
+            .is_equal_to(Some(Decimal::from_str("7922816251426433759354395033.5")?));
+
+        let transaction = deserialize("0.1234567890123456789")?;
+        assert_that!(transaction.amount.map(|a| a.to_string()))
+            .is_equal_to(Some("0.1234567890123456789".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_amount_with_sign() -> anyhow::Result<()> {
+        let transaction = deserialize("+10.00")?;
+        assert_that!(transaction.amount.map(|a| a.to_string()))
+            .is_equal_to(Some("10.00".to_string()));
+        assert_that!(transaction.validate()).is_equal_to(Ok(()));
+
+        // Negative amounts still go through validation
+        let transaction = deserialize("-10.00")?;
+        assert_that!(transaction.validate()).is_equal_to(Err(ValidationError::NonPositiveAmount {
+            tx: TxId(1),
+            amount: Decimal::from_str("-10.00")?,
+        }));
+
+        for amount in ["++10", "+-10", "+"] {
+            assert!(deserialize(amount).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_amount_out_of_range() {
+        let error = deserialize("99999999999999999999999999999999999999.0").unwrap_err();
+        assert!(error.to_string().contains("invalid amount"));
+    }
+
+    #[test]
+    fn test_amount_from_cents() -> anyhow::Result<()> {
+        let mut transaction = deserialize("1050")?;
+        transaction.amount_from_cents()?;
+        assert_that!(transaction.amount).is_equal_to(Some(Decimal::from_str("10.50")?));
+        assert_that!(transaction.amount.map(|a| a.to_string()))
+            .is_equal_to(Some("10.50".to_string()));
+
+        let mut transaction = deserialize("10.5")?;
+        assert_that!(transaction.amount_from_cents())
+            .is_equal_to(Err(TransactionError::FractionalCents(TxId(1))));
+
+        Ok(())
+    }
+
+    fn transaction(r#type: TransactionType, amount: Option<Decimal>) -> Transaction {
+        Transaction {
+            r#type,
+            tx: TxId(1),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_schema() -> anyhow::Result<()> {
+        assert_that!(deserialize("1.0")?.check_schema(1, &Handlers::default())).is_equal_to(Ok(()));
+        assert_that!(deserialize("")?.check_schema(3, &Handlers::default())).is_equal_to(Err(
+            SchemaError::AmountRequired {
+                r#type: "deposit".to_string(),
+                row: 3,
+            },
+        ));
+        assert_that!(
+            transaction(TransactionType::Dispute, None).check_schema(1, &Handlers::default())
+        )
+        .is_equal_to(Ok(()));
+        let fee = transaction(TransactionType::Custom("fee".to_string()), Some(dec!(1)));
+        assert_that!(fee.check_schema(2, &Handlers::default())).is_equal_to(Err(
+            SchemaError::UnknownType {
+                r#type: "fee".to_string(),
+                row: 2,
+            },
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_deposit() {
+        let amount = Some(Decimal::ONE);
+        assert_that!(transaction(TransactionType::Deposit, amount).validate()).is_equal_to(Ok(()));
+        assert_that!(transaction(TransactionType::Deposit, None).validate())
+            .is_equal_to(Err(ValidationError::MissingAmount(TxId(1))));
+        assert_that!(transaction(TransactionType::Deposit, Some(Decimal::ZERO)).validate())
+            .is_equal_to(Err(ValidationError::NonPositiveAmount {
+                tx: TxId(1),
+                amount: Decimal::ZERO,
+            }));
+    }
+
+    #[test]
+    fn test_validate_widthdrawal() {
+        let amount = Some(Decimal::ONE);
+        assert_that!(transaction(TransactionType::Widthdrawal, amount).validate())
+            .is_equal_to(Ok(()));
+        assert_that!(transaction(TransactionType::Widthdrawal, None).validate())
+            .is_equal_to(Err(ValidationError::MissingAmount(TxId(1))));
+        assert_that!(transaction(TransactionType::Widthdrawal, Some(-Decimal::ONE)).validate())
+            .is_equal_to(Err(ValidationError::NonPositiveAmount {
+                tx: TxId(1),
+                amount: -Decimal::ONE,
+            }));
+    }
+
+    #[test]
+    fn test_validate_disputes() {
+        for r#type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            assert_that!(transaction(r#type.clone(), None).validate()).is_equal_to(Ok(()));
+        }
+        assert_that!(transaction(TransactionType::Dispute, Some(Decimal::ONE)).validate())
+            .is_equal_to(Err(ValidationError::UnexpectedAmount(TxId(1))));
+        // Checked against the disputed amount when applied
+        for r#type in [TransactionType::Resolve, TransactionType::Chargeback] {
+            assert_that!(transaction(r#type, Some(Decimal::ONE)).validate()).is_equal_to(Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_check_decimals() -> anyhow::Result<()> {
+        assert_that!(deserialize("1.2345")?.check_decimals(4)).is_equal_to(Ok(()));
+        assert_that!(deserialize("1.234500")?.check_decimals(4)).is_equal_to(Ok(()));
+        assert_that!(deserialize("12345")?.check_decimals(0)).is_equal_to(Ok(()));
+        assert_that!(deserialize("1.23456")?.check_decimals(4)).is_equal_to(Err(
+            ValidationError::TooManyDecimals {
+                tx: TxId(1),
+                places: 5,
+            },
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rounded() -> anyhow::Result<()> {
+        assert_that!(deserialize("1.005")?.rounded(2).amount).is_equal_to(Some(dec!(1.00)));
+        assert_that!(deserialize("1.015")?.rounded(2).amount).is_equal_to(Some(dec!(1.02)));
+        assert_that!(deserialize("1.5")?.rounded(2).amount).is_equal_to(Some(dec!(1.5)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_client_max() -> anyhow::Result<()> {
+        let transaction = deserialize("1.0")?;
+        assert_that!(transaction.check_client_max(1)).is_equal_to(Ok(()));
+        let swapped = Transaction {
+            client: ClientId(4242),
+            tx: TxId(1),
+            ..transaction
+        };
+        assert_that!(swapped.check_client_max(1000)).is_equal_to(Err(
+            ValidationError::ClientOverMax {
+                tx: TxId(1),
+                client: ClientId(4242),
+                max: 1000,
+            },
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_max_amount() -> anyhow::Result<()> {
+        let transaction = deserialize("100.0")?;
+        assert_that!(transaction.check_max_amount(dec!(100))).is_equal_to(Ok(()));
+        assert_that!(transaction.check_max_amount(dec!(99.99))).is_equal_to(Err(
+            ValidationError::AmountOverMax {
+                tx: TxId(1),
+                amount: dec!(100.0),
+                max: dec!(99.99),
+            },
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_unicode_cleanup() -> anyhow::Result<()> {
+        for (name, r#type) in [
+            ("deposit\u{a0}", TransactionType::Deposit),
+            ("\u{a0}\u{2009}dispute", TransactionType::Dispute),
+            ("deposit\u{30a}", TransactionType::Deposit),
+            ("chargeback", TransactionType::Chargeback),
+        ] {
+            let record = ByteRecord::from(vec![name, "1", "1", ""]);
+            let transaction: Transaction = record.deserialize(None)?;
+            assert_that!(transaction.r#type).is_equal_to(r#type);
+        }
+        // NFC composes what can be, "é" spelled with a combining accent is still "é"
+        assert_that!(TransactionType::clean("de\u{301}pot\u{302}"))
+            .is_equal_to("dépot".to_string());
+        assert_that!(TransactionType::from_alias("Withdraw\u{a0}"))
+            .is_equal_to(Some(TransactionType::Widthdrawal));
+        let record = ByteRecord::from(vec!["refund\u{a0}", "1", "1", ""]);
+        let transaction: Transaction = record.deserialize(None)?;
+        assert_that!(transaction.r#type).is_equal_to(TransactionType::Custom("refund".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_aliases() {
+        for (aliases, r#type) in [
+            (
+                vec!["deposit", "Deposits", "DEPOSIT"],
+                TransactionType::Deposit,
+            ),
+            (
+                vec!["withdraw", "withdrawal", "withdrawals", "Widthdrawals"],
+                TransactionType::Widthdrawal,
+            ),
+            (vec!["disputes", "Dispute"], TransactionType::Dispute),
+            (vec!["resolves", "resolved"], TransactionType::Resolve),
+            (
+                vec!["chargebacks", "charge-back", "charge_back"],
+                TransactionType::Chargeback,
+            ),
+        ] {
+            for alias in aliases {
+                assert_that!(TransactionType::from_alias(alias)).is_equal_to(Some(r#type.clone()));
+            }
+            // The canonical name is what the input is deserialized from
+            let record = ByteRecord::from(vec![r#type.name(), "1", "1", ""]);
+            assert_that!(record.deserialize::<Transaction>(None).unwrap().r#type)
+                .is_equal_to(r#type);
+        }
+        assert_that!(TransactionType::from_alias("refund")).is_equal_to(None);
+    }
+
+    #[test]
+    fn test_ids_deserialize_as_integers() -> anyhow::Result<()> {
+        let transaction: Transaction =
+            ByteRecord::from(vec!["dispute", "2", "7", ""]).deserialize(None)?;
+        assert_that!(transaction.client).is_equal_to(ClientId(2));
+        assert_that!(transaction.tx).is_equal_to(TxId(7));
+
+        assert!(ByteRecord::from(vec!["dispute", "70000", "7", ""])
+            .deserialize::<Transaction>(None)
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+
+use crate::options::Options;
+use crate::parser::{parse_into_engine, Engine};
+
+/// Generates a CSV of `transactions` random transactions spread over `clients` clients.
+/// Disputes, resolves and chargebacks reference earlier deposits of the same client.
+pub fn generate_data(transactions: u32, clients: u16, seed: u64) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut deposits: HashMap<u16, Vec<u32>> = HashMap::new();
+    let mut data = "type,client,tx,amount\n".to_string();
+
+    for tx in 1..=transactions {
+        let client = rng.gen_range(1..=clients.max(1));
+        let amount = format!("{}.{:04}", rng.gen_range(0..1000), rng.gen_range(0..10_000));
+        let past_deposit = deposits
+            .get(&client)
+            .filter(|txs| !txs.is_empty())
+            .map(|txs| txs[rng.gen_range(0..txs.len())]);
+
+        let row = match (rng.gen_range(0..100), past_deposit) {
+            (55..=79, _) => format!("widthdrawal,{},{},{}\n", client, tx, amount),
+            (80..=89, Some(past)) => format!("dispute,{},{},\n", client, past),
+            (90..=94, Some(past)) => format!("resolve,{},{},\n", client, past),
+            (95..=99, Some(past)) => format!("chargeback,{},{},\n", client, past),
+            _ => {
+                deposits.entry(client).or_default().push(tx);
+                format!("deposit,{},{},{}\n", client, tx, amount)
+            }
+        };
+        data.push_str(&row);
+    }
+
+    data.into_bytes()
+}
+
+/// Runs generated data through the engine and checks its invariants hold
+pub async fn self_test(transactions: u32, clients: u16, seed: u64) -> anyhow::Result<Engine> {
+    let data = generate_data(transactions, clients, seed);
+    let engine = parse_into_engine(data.as_slice(), &Options::default(), &mut io::sink()).await?;
+    engine.check_invariants()?;
+    Ok(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+
+    #[test]
+    fn test_generate_data_is_deterministic() {
+        let data = generate_data(100, 5, 42);
+        assert_that!(data).is_equal_to(generate_data(100, 5, 42));
+        assert_that!(String::from_utf8(data).unwrap().lines().count()).is_equal_to(101);
+    }
+
+    #[tokio::test]
+    async fn test_self_test() -> anyhow::Result<()> {
+        let engine = self_test(1_000, 10, 7).await?;
+        assert_that!(engine.clients().len()).is_equal_to(10);
+        Ok(())
+    }
+}
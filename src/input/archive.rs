@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use zip::ZipArchive;
+
+use super::Input;
+
+/// Reads the CSV entries of the `.zip` archive at `path`, in name order. Entries are
+/// decompressed into memory up front, other files of the archive are ignored.
+pub async fn open(path: &str) -> anyhow::Result<Vec<Input>> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || read_entries(File::open(path)?)).await?
+}
+
+fn read_entries<R: Read + Seek>(reader: R) -> anyhow::Result<Vec<Input>> {
+    let mut archive = ZipArchive::new(reader)?;
+    let mut names = archive
+        .file_names()
+        .filter(|name| name.to_ascii_lowercase().ends_with(".csv"))
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    names.sort();
+
+    names
+        .iter()
+        .map(|name| {
+            let mut entry = archive.by_name(name)?;
+            let mut data = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut data)?;
+            Ok(Box::new(Cursor::new(data)) as Input)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use std::io::{self, Write};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use crate::options::Options;
+    use crate::parser::{parse_chained_into_engine, parse_transactions};
+
+    #[tokio::test]
+    async fn test_zip_entries_in_name_order() -> anyhow::Result<()> {
+        let first = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,4.0\n";
+        let second = "type,client,tx,amount\nwidthdrawal,1,3,2.5\ndispute,2,2,\n";
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in [
+            ("2022-01-02.csv", second),
+            ("README.txt", "daily exports"),
+            ("2022-01-01.csv", first),
+        ] {
+            zip.start_file(name, FileOptions::default())?;
+            zip.write_all(content.as_bytes())?;
+        }
+        let archive = zip.finish()?;
+
+        let entries = read_entries(archive)?;
+        assert_that!(entries).has_length(2);
+        let options = Options::default();
+        let clients = parse_chained_into_engine(entries, &options, &mut io::sink())
+            .await?
+            .into_clients();
+
+        let concatenated = format!("{}{}", first, second.split_once('\n').unwrap().1);
+        let expected =
+            parse_transactions(concatenated.as_bytes(), &options, &mut io::sink()).await?;
+        assert_that!(clients).is_equal_to(expected);
+
+        Ok(())
+    }
+}
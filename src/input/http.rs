@@ -0,0 +1,77 @@
+use std::io;
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use tokio_util::io::StreamReader;
+
+/// Streams the body of `url` as it's downloaded, failing on non-success statuses
+pub async fn open(url: &str) -> anyhow::Result<impl AsyncRead + Unpin + Send> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    let body = response
+        .bytes_stream()
+        .map(|chunk| chunk.map_err(io::Error::other));
+    Ok(StreamReader::new(Box::pin(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use rust_decimal_macros::dec;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::entities::id::ClientId;
+    use crate::options::Options;
+    use crate::parser::parse_transactions;
+
+    /// Serves a single request with `status` and `body`, returning the server URL
+    async fn mock_server(status: &'static str, body: &'static str) -> anyhow::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/transactions.csv", listener.local_addr()?);
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            // Reads the request headers, a GET has no body
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = socket.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await?;
+            socket.shutdown().await
+        });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_open_url() -> anyhow::Result<()> {
+        let url = mock_server(
+            "200 OK",
+            "type,client,tx,amount\ndeposit,1,1,3.0\nwidthdrawal,1,2,1.5\n",
+        )
+        .await?;
+        let clients =
+            parse_transactions(open(&url).await?, &Options::default(), &mut io::sink()).await?;
+
+        assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(1.5));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_url_not_found() -> anyhow::Result<()> {
+        let url = mock_server("404 Not Found", "").await?;
+        assert!(open(&url).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+#[cfg(feature = "zip")]
+pub mod archive;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+
+#[cfg(any(not(feature = "http"), not(feature = "zip"), not(feature = "parquet")))]
+use anyhow::bail;
+use std::io::Cursor;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Opened input, whatever its source
+pub type Input = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Opens `input`: a file path, or an `http(s)://` URL when built with the http feature
+pub async fn open(input: &str) -> anyhow::Result<Input> {
+    if input.starts_with("http://") || input.starts_with("https://") {
+        #[cfg(feature = "http")]
+        return Ok(Box::new(http::open(input).await?));
+        #[cfg(not(feature = "http"))]
+        bail!(
+            "Can't read {}, HTTP support requires the http feature",
+            input
+        );
+    }
+    Ok(Box::new(File::open(input).await?))
+}
+
+/// Opens `input` as the list of inputs to apply one after the other: the CSV entries of a `.zip`
+/// archive in name order when built with the zip feature, else `input` alone
+pub async fn open_all(input: &str) -> anyhow::Result<Vec<Input>> {
+    if input.to_ascii_lowercase().ends_with(".zip") {
+        #[cfg(feature = "zip")]
+        return archive::open(input).await;
+        #[cfg(not(feature = "zip"))]
+        bail!("Can't read {}, zip archives require the zip feature", input);
+    }
+    Ok(vec![open(input).await?])
+}
+
+/// Whether `input` is a Parquet file of transactions, its path ending in `.parquet`
+pub fn is_parquet(input: &str) -> bool {
+    input.to_ascii_lowercase().ends_with(".parquet")
+}
+
+/// Records read lazily from an input which isn't CSV, see [`read_parquet`]
+pub type Records = Box<dyn Iterator<Item = anyhow::Result<csv_async::ByteRecord>> + Send>;
+
+/// Reads the Parquet file `input` as records when built with the parquet feature, see
+/// [`parquet::read_records`]
+pub fn read_parquet(input: &str) -> anyhow::Result<Records> {
+    #[cfg(feature = "parquet")]
+    return Ok(Box::new(parquet::read_records(input)?));
+    #[cfg(not(feature = "parquet"))]
+    bail!(
+        "Can't read {}, Parquet support requires the parquet feature",
+        input
+    );
+}
+
+/// `input` preceded by a `headers` line, for inputs without one
+pub fn with_headers(input: Input, headers: &str) -> Input {
+    Box::new(Cursor::new(format!("{}\n", headers).into_bytes()).chain(input))
+}
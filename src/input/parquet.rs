@@ -0,0 +1,167 @@
+use anyhow::bail;
+use arrow_array::cast::AsArray;
+use arrow_array::types::{
+    Decimal128Type, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type,
+    UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+};
+use arrow_array::{Array, RecordBatch};
+use arrow_schema::DataType;
+use csv_async::ByteRecord;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::fs::File;
+
+use crate::entities::transaction::Transaction;
+
+/// Reads the rows of the Parquet file at `path` as records of the [`Transaction::headers`]
+/// columns, to be deserialized like CSV rows. Columns are found by name, `type`, `client` and
+/// `tx` being required, and their values written as text: strings as is, integers, floats and
+/// decimals in their usual notation, nulls as empty fields. Records are read lazily, a record
+/// batch at a time, a batch which couldn't be read giving an error.
+pub fn read_records(
+    path: &str,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<ByteRecord>> + Send> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+    Ok(reader.flat_map(|batch| {
+        let (records, error) = match batch
+            .map_err(anyhow::Error::from)
+            .and_then(|batch| read_batch(&batch))
+        {
+            Ok(records) => (records, None),
+            Err(e) => (vec![], Some(e)),
+        };
+        records.into_iter().map(Ok).chain(error.map(Err))
+    }))
+}
+
+fn read_batch(batch: &RecordBatch) -> anyhow::Result<Vec<ByteRecord>> {
+    let columns = Transaction::headers()
+        .into_iter()
+        .map(|name| match batch.column_by_name(name) {
+            Some(column) => Ok(Some(column)),
+            None if matches!(name, "type" | "client" | "tx") => {
+                bail!("Parquet input without a {} column", name)
+            }
+            None => Ok(None),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut records = Vec::with_capacity(batch.num_rows());
+    for row in 0..batch.num_rows() {
+        let fields = columns
+            .iter()
+            .map(|column| match column {
+                Some(column) => field(column.as_ref(), row),
+                None => Ok(String::new()),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        records.push(ByteRecord::from(fields));
+    }
+    Ok(records)
+}
+
+/// Value of `column` at `row` as text
+fn field(column: &dyn Array, row: usize) -> anyhow::Result<String> {
+    if column.is_null(row) {
+        return Ok(String::new());
+    }
+    let value = match column.data_type() {
+        DataType::Utf8 => column.as_string::<i32>().value(row).to_string(),
+        DataType::LargeUtf8 => column.as_string::<i64>().value(row).to_string(),
+        DataType::Int8 => column.as_primitive::<Int8Type>().value(row).to_string(),
+        DataType::Int16 => column.as_primitive::<Int16Type>().value(row).to_string(),
+        DataType::Int32 => column.as_primitive::<Int32Type>().value(row).to_string(),
+        DataType::Int64 => column.as_primitive::<Int64Type>().value(row).to_string(),
+        DataType::UInt8 => column.as_primitive::<UInt8Type>().value(row).to_string(),
+        DataType::UInt16 => column.as_primitive::<UInt16Type>().value(row).to_string(),
+        DataType::UInt32 => column.as_primitive::<UInt32Type>().value(row).to_string(),
+        DataType::UInt64 => column.as_primitive::<UInt64Type>().value(row).to_string(),
+        DataType::Float32 => column.as_primitive::<Float32Type>().value(row).to_string(),
+        DataType::Float64 => column.as_primitive::<Float64Type>().value(row).to_string(),
+        DataType::Decimal128(_, _) => column.as_primitive::<Decimal128Type>().value_as_string(row),
+        other => bail!("Unsupported Parquet column type {}", other),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{ArrayRef, Decimal128Array, StringArray, UInt16Array, UInt32Array};
+    use arrow_schema::{Field, Schema};
+    use assertor::*;
+    use parquet::arrow::ArrowWriter;
+    use std::io;
+    use std::sync::Arc;
+
+    use crate::options::Options;
+    use crate::parser::{parse_records_into_engine, parse_transactions, write_clients};
+
+    #[tokio::test]
+    async fn test_parquet_matches_csv() -> anyhow::Result<()> {
+        let csv: &[u8] = b"type,client,tx,amount
+deposit,2,1,2.0
+deposit,1,2,1.5
+widthdrawal,1,3,0.25
+dispute,2,1,
+chargeback,2,1,
+widthdrawal,1,4,5.0
+";
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("type", DataType::Utf8, false),
+            Field::new("client", DataType::UInt16, false),
+            Field::new("tx", DataType::UInt32, false),
+            Field::new("amount", DataType::Decimal128(10, 2), true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    "deposit",
+                    "deposit",
+                    "widthdrawal",
+                    "dispute",
+                    "chargeback",
+                    "widthdrawal",
+                ])) as ArrayRef,
+                Arc::new(UInt16Array::from(vec![2, 1, 1, 2, 2, 1])),
+                Arc::new(UInt32Array::from(vec![1, 2, 3, 1, 1, 4])),
+                Arc::new(
+                    Decimal128Array::from(vec![
+                        Some(200),
+                        Some(150),
+                        Some(25),
+                        None,
+                        None,
+                        Some(500),
+                    ])
+                    .with_precision_and_scale(10, 2)?,
+                ),
+            ],
+        )?;
+        let path = std::env::temp_dir().join(format!("ex-input-{}.parquet", std::process::id()));
+        let mut writer = ArrowWriter::try_new(File::create(&path)?, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+
+        let options = Options::default();
+        let path = path.to_str().unwrap();
+        let record = read_records(path)?.nth(2).transpose()?;
+        assert_that!(record).is_equal_to(Some(ByteRecord::from(vec![
+            "widthdrawal",
+            "1",
+            "3",
+            "0.25",
+            "",
+            "",
+        ])));
+
+        let engine = parse_records_into_engine(read_records(path)?, &options, &mut io::sink())?;
+        std::fs::remove_file(path)?;
+        let from_parquet = write_clients(&engine.into_clients(), &options, vec![]).await?;
+        let clients = parse_transactions(csv, &options, &mut io::sink()).await?;
+        let from_csv = write_clients(&clients, &options, vec![]).await?;
+        assert_that!(String::from_utf8(from_parquet)?).is_equal_to(String::from_utf8(from_csv)?);
+
+        Ok(())
+    }
+}
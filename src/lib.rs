@@ -0,0 +1,9 @@
+pub mod diff;
+pub mod entities;
+pub mod generator;
+pub mod input;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod options;
+pub mod output;
+pub mod parser;
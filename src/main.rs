@@ -1,17 +1,54 @@
-pub mod entities;
-mod parser;
+use clap::Parser;
+use std::fs::File;
+use std::io;
+use std::process;
+use std::sync::atomic::Ordering;
+use tokio::signal;
 
-use std::env;
+use ex::options::{Command, Options};
+use ex::parser;
+use ex::{diff, generator};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = env::args().collect::<Vec<String>>();
-    if args.len() != 2 {
-        panic!("Call with filename input")
-    }
-    let file_name: &String = args.last().unwrap();
+    let options = Options::parse();
+
+    match options.command {
+        Some(Command::SelfTest {
+            transactions,
+            clients,
+            seed,
+        }) => {
+            let engine = generator::self_test(transactions, clients, seed).await?;
+            eprintln!(
+                "Self test passed: {} transactions over {} clients",
+                transactions,
+                engine.clients().len()
+            );
+        }
+        Some(Command::Diff { before, after }) => {
+            let diffs = diff::diff_outputs(File::open(before)?, File::open(after)?)?;
+            diff::write_diff(&diffs, io::stdout())?;
+            if !diffs.is_empty() {
+                process::exit(1);
+            }
+        }
+        None => {
+            let interrupt = options.interrupt.clone();
+            tokio::spawn(async move {
+                if signal::ctrl_c().await.is_ok() {
+                    eprintln!("Interrupted, writing the clients processed so far");
+                    interrupt.store(true, Ordering::Relaxed);
+                }
+                // A second SIGINT doesn't wait for the output
+                if signal::ctrl_c().await.is_ok() {
+                    process::exit(130);
+                }
+            });
 
-    eprintln!("Parsing {}", file_name);
-    parser::parse_data(file_name.as_str()).await?;
+            eprintln!("Parsing {}", options.input.as_deref().unwrap_or_default());
+            parser::parse_data(&options).await?;
+        }
+    }
     Ok(())
 }
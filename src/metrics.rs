@@ -0,0 +1,160 @@
+use std::fmt::Write as _;
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::parser::Summary;
+
+/// Engine counters exposed in the Prometheus text format, for a server embedding the engine.
+/// The embedder records the [`Summary`] of its engine as often as it sees fit, scrapes get the
+/// last recorded one.
+#[derive(Default)]
+pub struct Metrics {
+    summary: RwLock<Summary>,
+}
+
+impl Metrics {
+    pub fn record(&self, summary: Summary) {
+        *self.summary.write().unwrap_or_else(|e| e.into_inner()) = summary;
+    }
+
+    /// Last recorded counters, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let summary = self.summary.read().unwrap_or_else(|e| e.into_inner());
+        let mut output = String::new();
+        for (name, kind, help, value) in [
+            (
+                "ex_transactions_applied_total",
+                "counter",
+                "Transactions which went through",
+                summary.stats.applied,
+            ),
+            (
+                "ex_transactions_rejected_total",
+                "counter",
+                "Transactions which were rejected",
+                summary.stats.rejected,
+            ),
+            (
+                "ex_rows_skipped_total",
+                "counter",
+                "Rows which couldn't be deserialized",
+                summary.stats.skipped,
+            ),
+            ("ex_clients", "gauge", "Known clients", summary.clients),
+            (
+                "ex_locked_accounts",
+                "gauge",
+                "Clients locked by a chargeback",
+                summary.locked,
+            ),
+        ] {
+            // Writing to a String can't fail
+            let _ = write!(
+                output,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        }
+        output
+    }
+}
+
+/// Answers `GET /metrics` on `listener` with the counters of `metrics` until the task is
+/// dropped, other paths get a 404
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // A failing scrape only concerns its own connection
+            let _ = respond(socket, &metrics).await;
+        });
+    }
+}
+
+async fn respond(socket: TcpStream, metrics: &Metrics) -> anyhow::Result<()> {
+    let mut socket = BufReader::new(socket);
+    let mut request_line = String::new();
+    socket.read_line(&mut request_line).await?;
+    // Skips the headers, a GET has no body
+    let mut line = String::new();
+    while socket.read_line(&mut line).await? > 2 {
+        line.clear();
+    }
+
+    let response = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    socket.get_mut().write_all(response.as_bytes()).await?;
+    socket.get_mut().shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use csv::ByteRecord;
+    use tokio::io::AsyncReadExt;
+
+    use crate::options::Options;
+    use crate::parser::Engine;
+
+    async fn scrape(url: &str, path: &str) -> anyhow::Result<String> {
+        let mut socket = TcpStream::connect(url).await?;
+        socket
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, url).as_bytes())
+            .await?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_scrape_metrics() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = listener.local_addr()?.to_string();
+        let metrics = Arc::new(Metrics::default());
+        tokio::spawn(serve(listener, metrics.clone()));
+
+        let mut engine = Engine::new(Options::default());
+        for record in [
+            vec!["deposit", "1", "1", "3.0"],
+            vec!["widthdrawal", "1", "2", "5.0"],
+            vec!["deposit", "2", "3", "1.0"],
+            vec!["dispute", "2", "3", ""],
+            vec!["chargeback", "2", "3", ""],
+        ] {
+            engine.apply_record(&ByteRecord::from(record))?;
+        }
+        metrics.record(engine.summary());
+
+        let response = scrape(&url, "/metrics").await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let samples = body
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_that!(samples).is_equal_to(vec![
+            "ex_transactions_applied_total 4",
+            "ex_transactions_rejected_total 1",
+            "ex_rows_skipped_total 0",
+            "ex_clients 2",
+            "ex_locked_accounts 1",
+        ]);
+
+        let response = scrape(&url, "/other").await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,723 @@
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use rust_decimal::Decimal;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::entities::client::Client;
+use crate::entities::handler::Handlers;
+use crate::entities::transaction::Transaction;
+use crate::output::OutputSink;
+
+/// Command line options
+#[derive(Parser, Debug, Default, Clone)]
+#[clap(
+    version,
+    about = "Processes a CSV of transactions and outputs the resulting client accounts",
+    subcommand_negates_reqs = true
+)]
+pub struct Options {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// CSV file holding the transactions, an `http(s)://` URL with the http feature or a `.zip`
+    /// archive of CSV files, applied in name order, with the zip feature
+    #[clap(required = true)]
+    pub input: Option<String>,
+
+    /// The inputs have no headers row, their columns are given by --schema
+    #[clap(long)]
+    pub no_headers: bool,
+
+    /// Comma separated columns of inputs without headers, in any order, e.g. `tx,client,type,amount`
+    #[clap(
+        long,
+        requires = "no-headers",
+        default_value_if("no-headers", None, Some(DEFAULT_SCHEMA))
+    )]
+    pub schema: Option<String>,
+
+    /// Other inputs sorted by `timestamp`, merged with INPUT in timestamp order
+    #[clap(long)]
+    pub merge: Vec<String>,
+
+    /// Apply INPUT then the --merge inputs one after the other instead of merging them, writing
+    /// the clients after each of them next to the --output file: `out.1.csv`, `out.2.csv`, ...
+    #[clap(long, requires = "output", conflicts_with_all = &["compact", "format"])]
+    pub snapshot_per_input: bool,
+
+    /// Read INPUT as a live feed, e.g. a FIFO, until its writer closes it, writing the clients
+    /// so far to the --output file every MS milliseconds
+    #[clap(
+        long,
+        value_name = "MS",
+        requires = "output",
+        conflicts_with_all = &[
+            "merge",
+            "snapshot-per-input",
+            "compact",
+            "format",
+            "shards",
+            "replay-from-snapshot",
+            "normalize-types",
+            "currency-symbol",
+            "decimal-separator"
+        ]
+    )]
+    pub snapshot_interval_ms: Option<u64>,
+
+    /// Where to write the clients: `-` for STDOUT, a CSV file path or `sqlite://path.db`
+    #[clap(long, default_value = "-")]
+    pub output: OutputSink,
+
+    /// Gzip the CSV clients, as done for an --output path ending in `.gz`
+    #[clap(
+        long,
+        conflicts_with_all = &["format", "coalesce-deposits", "snapshot-per-input", "snapshot-interval-ms"]
+    )]
+    pub gzip_output: bool,
+
+    /// Start the CSV output with a `#` comment line giving the tool version and the time of
+    /// the run, which standard CSV parsers don't expect
+    #[clap(long)]
+    pub header_comment: bool,
+
+    /// Instead of the clients, write the applied deposits and withdrawals in the input format,
+    /// each run of consecutive deposits of a client coalesced into one. Disputes aren't written.
+    #[clap(long, conflicts_with_all = &["compact", "format", "snapshot-per-input"])]
+    pub coalesce_deposits: bool,
+
+    /// Write a single line JSON summary of the run on STDOUT instead of the clients
+    #[clap(long, conflicts_with_all = &["output", "format"])]
+    pub compact: bool,
+
+    /// Write the summary of the run on STDERR in this format, or on STDOUT in this format
+    /// instead of JSON with --compact
+    #[clap(long, value_enum, conflicts_with = "snapshot-per-input")]
+    pub report_format: Option<ReportFormat>,
+
+    /// Format of the clients written to an --output file
+    #[clap(long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub format: OutputFormat,
+
+    /// Unit of the input amounts
+    #[clap(long, value_enum, default_value_t = AmountUnit::Decimal)]
+    pub amount_unit: AmountUnit,
+
+    /// Whether disputes may make available funds negative
+    #[clap(long, value_enum, default_value_t = AccountPolicy::Strict)]
+    pub account_policy: AccountPolicy,
+
+    /// Reject transactions whose amount has more decimal places than this
+    #[clap(long, value_name = "PLACES")]
+    pub max_decimals: Option<u32>,
+
+    /// Reject transactions carrying an amount over this cap
+    #[clap(long, value_name = "AMOUNT", value_parser = positive_amount)]
+    pub max_amount: Option<Decimal>,
+
+    /// Reject transactions whose client id is over this, flagging inputs whose client and tx
+    /// columns were likely swapped
+    #[clap(long, value_name = "CLIENT")]
+    pub expect_client_max: Option<u16>,
+
+    /// Round deposit and withdrawal amounts as they're applied to the scale of their currency
+    /// when known, else to this many decimal places, halves to even. Balances then never carry
+    /// sub-scale dust.
+    #[clap(long, value_name = "PLACES")]
+    pub round_on_apply: Option<u32>,
+
+    /// Still accept a withdrawal exceeding available funds by at most this amount, e.g. because
+    /// of rounding upstream or as the overdraft a ledger permits. Available funds then go
+    /// negative, down to minus this amount.
+    #[clap(
+        long,
+        visible_alias = "overdraft-limit",
+        value_name = "AMOUNT",
+        value_parser = non_negative_amount
+    )]
+    pub deny_withdrawal_overdraft_epsilon: Option<Decimal>,
+
+    /// Warn when a dispute takes the funds held by a client over this amount
+    #[clap(long, value_name = "AMOUNT")]
+    pub held_threshold: Option<Decimal>,
+
+    /// Fail instead of warning when --held-threshold is exceeded
+    #[clap(long, requires = "held-threshold")]
+    pub strict_held_threshold: bool,
+
+    /// What to do with the disputes still open at the end of the input
+    #[clap(long, value_enum, default_value_t = OpenDisputes::Hold)]
+    pub open_disputes: OpenDisputes,
+
+    /// Allow disputing withdrawals: the amount is held until resolved, a chargeback credits it back
+    #[clap(long)]
+    pub dispute_withdrawals: bool,
+
+    /// Fail once more than N client accounts (one per client and currency) would be created
+    #[clap(long)]
+    pub limit_clients: Option<usize>,
+
+    /// Only output locked clients
+    #[clap(long, conflicts_with = "exclude-locked")]
+    pub output_locked_only: bool,
+
+    /// Leave locked clients out of the output
+    #[clap(long)]
+    pub exclude_locked: bool,
+
+    /// Report the clients whose available funds are negative once all transactions are
+    /// applied, which disputes allow with --account-policy allow-negative
+    #[clap(long)]
+    pub no_negative_available: bool,
+
+    /// Leave the clients with negative available funds out of the output
+    #[clap(long, requires = "no-negative-available")]
+    pub exclude_negative_available: bool,
+
+    /// Leave out clients none of whose transactions went through, e.g. with only rejected
+    /// withdrawals
+    #[clap(long)]
+    pub omit_inactive: bool,
+
+    /// Round the output amounts of a currency to N decimal places, e.g. `BTC=8`, overriding the
+    /// built-in scales of common currencies
+    #[clap(long, value_name = "CURRENCY=N")]
+    pub currency_scale: Vec<CurrencyScale>,
+
+    /// Add the gross_deposits, gross_withdrawals and net_deposits columns to the CSV output
+    #[clap(long)]
+    pub gross_totals: bool,
+
+    /// Add the available_cents, held_cents and total_cents columns, amounts in whole cents, to
+    /// the CSV output
+    #[clap(long)]
+    pub cents_columns: bool,
+
+    /// Print on STDERR a SHA-256 of the output, sorted by client, to compare runs cheaply
+    #[clap(long, conflicts_with_all = &["compact", "coalesce-deposits"])]
+    pub emit_checksum: bool,
+
+    /// Order of the output clients, ties being ordered by client id
+    #[clap(long, value_enum, default_value_t = SortBy::Client)]
+    pub sort_by: SortBy,
+
+    /// Write clients unsorted straight to STDOUT instead of building the sorted output in memory
+    #[clap(long)]
+    pub streaming_output: bool,
+
+    /// With --streaming-output, flush the output every N clients
+    #[clap(long, default_value_t = 1000)]
+    pub flush_every: usize,
+
+    /// With --streaming-output, also flush the output once this many milliseconds went by since
+    /// the previous flush, checked as clients are written, so a slow sink gets them in time
+    #[clap(long, value_name = "MS", requires = "streaming-output")]
+    pub flush_interval_ms: Option<u64>,
+
+    /// Number of record chunks deserialized in parallel ahead of processing, 0 reads serially.
+    /// Transactions are still applied in their original order.
+    #[clap(long, default_value_t = 0)]
+    pub read_ahead: usize,
+
+    /// Read records by batches of N before deserializing them, 0 deserializes them one at a
+    /// time as they're read. Not used with --read-ahead.
+    #[clap(long, default_value_t = 0)]
+    pub batch_size: usize,
+
+    /// Fail when the input holds columns other than `type,client,tx,amount[,currency,timestamp]`
+    #[clap(long)]
+    pub strict_columns: bool,
+
+    /// Accept alternate spellings of the transaction types, e.g. `deposits`, `withdraw` or
+    /// `Chargebacks`
+    #[clap(long, conflicts_with = "merge")]
+    pub normalize_types: bool,
+
+    /// Currency symbol to strip from the amounts, before or after them, e.g. `$` for `$10.00`
+    #[clap(long, value_name = "SYMBOL", conflicts_with = "merge")]
+    pub currency_symbol: Option<String>,
+
+    /// Decimal separator of the amounts, e.g. `,` for `5,00` which must then be quoted. Amounts
+    /// holding a `.` are then invalid.
+    #[clap(long, value_name = "CHAR", conflicts_with = "merge")]
+    pub decimal_separator: Option<char>,
+
+    /// Only process rows from this one on (1-based, headers excluded). Disputes, resolves and
+    /// chargebacks of transactions from skipped rows are rejected.
+    #[clap(long)]
+    pub begin: Option<usize>,
+
+    /// Stop processing after this row (1-based, inclusive, headers excluded)
+    #[clap(long)]
+    pub end: Option<usize>,
+
+    /// Apply the rows on this many engines in parallel, each owning the clients whose id
+    /// modulo SHARDS is its index. Rows of a client keep their order.
+    #[clap(
+        long,
+        default_value_t = 0,
+        conflicts_with_all = &[
+            "merge",
+            "begin",
+            "end",
+            "audit-log",
+            "snapshot-per-input",
+            "replay-from-snapshot",
+            "currency-symbol",
+            "decimal-separator",
+            "restore-maps",
+            "since-tx",
+            "require-sorted-tx",
+            "all-or-nothing",
+            "initial-state",
+            "dump-maps",
+            "trace-state",
+            "explain",
+            "held-threshold",
+            "no-negative-available",
+            "normalize-types",
+            "log-format",
+            "limit-clients",
+            "require-monotonic",
+            "read-ahead",
+            "batch-size"
+        ]
+    )]
+    pub shards: usize,
+
+    /// Self-check: also process the input up to this row, restore a new engine from a snapshot
+    /// of its maps and apply the remaining rows, failing unless it ends like the full run
+    #[clap(long, value_name = "ROW", conflicts_with_all = &["begin", "end", "merge", "snapshot-per-input"])]
+    pub replay_from_snapshot: Option<usize>,
+
+    /// Reject transactions whose `timestamp` is before the previous one
+    #[clap(long)]
+    pub require_monotonic: bool,
+
+    /// Fail when the id of a deposit, withdrawal or custom transaction isn't greater than the
+    /// previous one, over the whole input or per client
+    #[clap(long, value_enum, value_name = "SCOPE")]
+    pub require_sorted_tx: Option<SortedTx>,
+
+    /// Process all the rows, then fail without writing any output if any of them was rejected
+    /// or invalid, listing them all
+    #[clap(long)]
+    pub all_or_nothing: bool,
+
+    /// Check the engine invariants once all transactions are applied, that every dispute keeps
+    /// the total of its client and that no withdrawal makes it negative, failing if they don't
+    /// hold
+    #[clap(long)]
+    pub verify_invariants: bool,
+
+    /// Start from the clients of a previous output, applying the input as a delta. Disputes of
+    /// transactions from before aren't known and are rejected.
+    #[clap(long)]
+    pub initial_state: Option<PathBuf>,
+
+    /// Only output the clients whose balances or lock differ from those of --initial-state,
+    /// e.g. to sync a delta
+    #[clap(
+        long,
+        requires = "initial-state",
+        conflicts_with_all = &["compact", "coalesce-deposits"]
+    )]
+    pub changed_only: bool,
+
+    /// Start from the clients, transactions history and open disputes written with
+    /// --dump-maps by a previous run, so disputes of its transactions still apply
+    #[clap(long, value_name = "PATH", conflicts_with = "initial-state")]
+    pub restore_maps: Option<PathBuf>,
+
+    /// Skip the deposits, withdrawals and custom transactions whose tx id is below TX, already
+    /// applied by the run whose state is restored, for feeds whose files overlap. Disputes,
+    /// resolves and chargebacks are still applied.
+    #[clap(long, value_name = "TX")]
+    pub since_tx: Option<u32>,
+
+    /// Write every input row to this CSV file, with whether it succeeded and why if it didn't
+    #[clap(long)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Format of the diagnostics about rejected and invalid rows written on STDERR
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Once the input is processed, write the clients, transactions history and disputed
+    /// transactions maps as JSON to this file, for debugging or to resume with --restore-maps
+    #[clap(long)]
+    pub dump_maps: Option<PathBuf>,
+
+    /// Write a JSON snapshot of the affected client to STDERR after each applied transaction
+    #[clap(long)]
+    pub trace_state: bool,
+
+    /// Describe on STDERR what happened to transaction TX and its client, row after row
+    #[clap(long, value_name = "TX")]
+    pub explain: Option<u32>,
+
+    /// Set to stop processing early, the clients computed so far are then written. Set on SIGINT.
+    #[clap(skip)]
+    pub interrupt: Arc<AtomicBool>,
+
+    /// Handlers of the custom transaction types, registered by embedders
+    #[clap(skip)]
+    pub handlers: Handlers,
+}
+
+/// Order of the output clients
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortBy {
+    /// Client id
+    #[default]
+    Client,
+    /// Increasing total
+    Total,
+    /// Increasing available funds
+    Available,
+}
+
+/// Over which transactions `--require-sorted-tx` expects increasing ids
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortedTx {
+    /// All of them
+    Global,
+    /// Those of each client
+    Client,
+}
+
+/// Format of the output file
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// Parquet file, requires the parquet feature
+    Parquet,
+}
+
+/// How the summary of a run is written, see `Summary::report`
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// A `name: value` line per field
+    Text,
+    /// A single line JSON object
+    #[default]
+    Json,
+    /// A header line and a line of values
+    Csv,
+}
+
+/// How diagnostics are written
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable messages
+    #[default]
+    Text,
+    /// A JSON object per rejected or invalid row, with its `row`, `tx`, `client` and `reason`
+    Json,
+}
+
+/// How amounts are written in the input
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AmountUnit {
+    /// Decimal amounts, e.g. `10.50`
+    #[default]
+    Decimal,
+    /// Integer amounts of minor units, e.g. `1050` for `10.50`
+    Cents,
+}
+
+/// How disputes of deposits whose funds were already withdrawn are handled
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccountPolicy {
+    /// Reject the dispute when available funds don't cover the disputed amount
+    #[default]
+    Strict,
+    /// Hold the disputed amount anyway, available funds going negative. A chargeback then
+    /// leaves a negative total, the loss.
+    AllowNegative,
+}
+
+/// How the disputes still open at the end of the input are handled, see `Engine::finalize`
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpenDisputes {
+    /// Leave their funds held, as if the input went on
+    #[default]
+    Hold,
+    /// Resolve them, releasing their funds
+    Resolve,
+}
+
+/// Decimal places of a currency's minor unit, given as `CURRENCY=N`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrencyScale {
+    pub currency: String,
+    pub scale: u32,
+}
+
+impl FromStr for CurrencyScale {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (currency, scale) = value
+            .split_once('=')
+            .with_context(|| format!("Expected CURRENCY=N, got {}", value))?;
+        Ok(CurrencyScale {
+            currency: currency.to_string(),
+            scale: scale.parse()?,
+        })
+    }
+}
+
+/// Parses an amount which can't be negative, a negative tolerance reversing its meaning
+fn non_negative_amount(value: &str) -> anyhow::Result<Decimal> {
+    let amount = Decimal::from_str(value)?;
+    if amount.is_sign_negative() && !amount.is_zero() {
+        bail!("Expected a non-negative amount, got {}", value);
+    }
+    Ok(amount)
+}
+
+/// Parses an amount which must be positive, a cap of zero or less rejecting every amount
+fn positive_amount(value: &str) -> anyhow::Result<Decimal> {
+    let amount = Decimal::from_str(value)?;
+    if !amount.is_sign_positive() || amount.is_zero() {
+        bail!("Expected a positive amount, got {}", value);
+    }
+    Ok(amount)
+}
+
+/// Built-in decimal places of common currencies
+const CURRENCY_SCALES: &[(&str, u32)] = &[
+    ("USD", 2),
+    ("EUR", 2),
+    ("GBP", 2),
+    ("CHF", 2),
+    ("CAD", 2),
+    ("AUD", 2),
+    ("JPY", 0),
+    ("KRW", 0),
+    ("BTC", 8),
+];
+
+/// Commands other than processing an input file
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Generates random transactions, runs them through the engine and checks its invariants
+    SelfTest {
+        /// Number of transactions to generate
+        #[clap(long, default_value_t = 10_000)]
+        transactions: u32,
+
+        /// Number of distinct clients
+        #[clap(long, default_value_t = 100)]
+        clients: u16,
+
+        /// Seed of the generator, the same seed always generates the same data
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Reports the clients whose balances differ between two outputs, with the deltas. Exits
+    /// with status 1 when there are any.
+    Diff {
+        /// Output taken as reference
+        before: PathBuf,
+        /// Output compared to it
+        after: PathBuf,
+    },
+}
+
+/// Columns of inputs without headers when no --schema is given
+const DEFAULT_SCHEMA: &str = "type,client,tx,amount";
+
+impl Options {
+    /// Headers row of inputs without one, checked against the known columns
+    pub fn schema(&self) -> anyhow::Result<String> {
+        let schema = self.schema.as_deref().unwrap_or(DEFAULT_SCHEMA);
+        let columns = schema.split(',').map(str::trim).collect::<Vec<_>>();
+        let known = Transaction::headers();
+        for (index, column) in columns.iter().enumerate() {
+            if !known.contains(column) {
+                bail!("Unknown column {} in --schema", column);
+            }
+            if columns[..index].contains(column) {
+                bail!("Column {} appears twice in --schema", column);
+            }
+        }
+        if let Some(column) = ["type", "client", "tx"]
+            .into_iter()
+            .find(|column| !columns.contains(column))
+        {
+            bail!("Missing column {} in --schema", column);
+        }
+        Ok(columns.join(","))
+    }
+
+    /// Decimal places the amounts of `currency` are rounded to in the output, if known
+    pub fn currency_scale(&self, currency: &str) -> Option<u32> {
+        self.currency_scale
+            .iter()
+            .rev()
+            .find(|scale| scale.currency == currency)
+            .map(|scale| scale.scale)
+            .or_else(|| {
+                CURRENCY_SCALES
+                    .iter()
+                    .find(|(known, _)| *known == currency)
+                    .map(|(_, scale)| *scale)
+            })
+    }
+
+    /// Whether the amounts are rewritten before being parsed, see `--currency-symbol` and
+    /// `--decimal-separator`
+    pub fn rewrites_amounts(&self) -> bool {
+        self.currency_symbol.is_some() || self.decimal_separator.is_some_and(|c| c != '.')
+    }
+
+    /// `client` as written in the output, whatever the sink: its amounts are rounded to the
+    /// scale of its currency when it's known
+    pub fn output_client(&self, client: &Client) -> Client {
+        match client
+            .currency
+            .as_deref()
+            .and_then(|currency| self.currency_scale(currency))
+        {
+            Some(scale) => client.rounded(scale),
+            None => client.clone(),
+        }
+    }
+
+    /// Whether `client` should appear in the output
+    pub fn outputs(&self, client: &Client) -> bool {
+        let negative = self.exclude_negative_available && client.available < Decimal::ZERO;
+        if (self.omit_inactive && !client.active) || negative {
+            false
+        } else if self.output_locked_only {
+            client.locked
+        } else if self.exclude_locked {
+            !client.locked
+        } else {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use clap::CommandFactory;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_options_definition() {
+        Options::command().debug_assert();
+    }
+
+    #[test]
+    fn test_shards_conflicts() {
+        // Shards apply rows on bare engines, what the processor takes care of isn't available,
+        // nor settings spanning clients
+        for flag in [
+            vec!["--all-or-nothing"],
+            vec!["--initial-state", "clients.csv"],
+            vec!["--dump-maps", "maps.json"],
+            vec!["--trace-state"],
+            vec!["--explain", "1"],
+            vec!["--held-threshold", "100"],
+            vec!["--no-negative-available"],
+            vec!["--normalize-types"],
+            vec!["--log-format", "json"],
+            vec!["--limit-clients", "10"],
+            vec!["--require-monotonic"],
+            vec!["--read-ahead", "4"],
+            vec!["--batch-size", "100"],
+        ] {
+            let args = [vec!["ex", "input.csv", "--shards", "2"], flag].concat();
+            assert!(Options::try_parse_from(args).is_err());
+        }
+        assert!(Options::try_parse_from(["ex", "input.csv", "--shards", "2"]).is_ok());
+    }
+
+    #[test]
+    fn test_output_only_conflicts() {
+        // Neither the summary nor the coalesced deposits are the clients output
+        for flag in [
+            vec!["--emit-checksum"],
+            vec!["--changed-only", "--initial-state", "clients.csv"],
+        ] {
+            for other in ["--compact", "--coalesce-deposits"] {
+                let args = [vec!["ex", "input.csv", other], flag.clone()].concat();
+                assert!(Options::try_parse_from(args).is_err());
+            }
+            let args = [vec!["ex", "input.csv"], flag].concat();
+            assert!(Options::try_parse_from(args).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_overdraft_limit_non_negative() {
+        for flag in ["--overdraft-limit", "--deny-withdrawal-overdraft-epsilon"] {
+            let negative = format!("{}=-3", flag);
+            let error = Options::try_parse_from(["ex", "input.csv", &negative]).unwrap_err();
+            assert_that!(error.to_string()).contains("Expected a non-negative amount");
+            let options = Options::try_parse_from(["ex", "input.csv", flag, "0.5"]).unwrap();
+            assert_eq!(options.deny_withdrawal_overdraft_epsilon, Some(dec!(0.5)));
+        }
+        assert!(Options::try_parse_from(["ex", "input.csv", "--overdraft-limit", "0"]).is_ok());
+    }
+
+    #[test]
+    fn test_max_amount_positive() {
+        for value in ["-3", "0"] {
+            let flag = format!("--max-amount={}", value);
+            let error = Options::try_parse_from(["ex", "input.csv", &flag]).unwrap_err();
+            assert_that!(error.to_string()).contains("Expected a positive amount");
+        }
+        let options = Options::try_parse_from(["ex", "input.csv", "--max-amount", "1000"]).unwrap();
+        assert_eq!(options.max_amount, Some(dec!(1000)));
+    }
+
+    #[test]
+    fn test_schema() -> anyhow::Result<()> {
+        let schema = |schema: &str| {
+            Options {
+                no_headers: true,
+                schema: Some(schema.to_string()),
+                ..Default::default()
+            }
+            .schema()
+        };
+        assert_that!(Options::default().schema()?).is_equal_to(DEFAULT_SCHEMA.to_string());
+        assert_that!(schema("tx, client,type,amount")?)
+            .is_equal_to("tx,client,type,amount".to_string());
+        assert_that!(schema("tx,client,kind").unwrap_err().to_string())
+            .is_equal_to("Unknown column kind in --schema".to_string());
+        assert_that!(schema("tx,client,tx").unwrap_err().to_string())
+            .is_equal_to("Column tx appears twice in --schema".to_string());
+        assert_that!(schema("tx,client,amount").unwrap_err().to_string())
+            .is_equal_to("Missing column type in --schema".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_currency_scale() -> anyhow::Result<()> {
+        let options = Options {
+            currency_scale: vec!["BTC=4".parse()?, "XAU=3".parse()?],
+            ..Default::default()
+        };
+        assert_that!(options.currency_scale("USD")).is_equal_to(Some(2));
+        assert_that!(options.currency_scale("JPY")).is_equal_to(Some(0));
+        assert_that!(options.currency_scale("BTC")).is_equal_to(Some(4));
+        assert_that!(options.currency_scale("XAU")).is_equal_to(Some(3));
+        assert_that!(options.currency_scale("ABC")).is_equal_to(None);
+        assert!("BTC".parse::<CurrencyScale>().is_err());
+        assert!("BTC=x".parse::<CurrencyScale>().is_err());
+
+        Ok(())
+    }
+}
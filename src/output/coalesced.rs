@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use tokio::io::AsyncWrite;
+
+use crate::entities::client::Client;
+use crate::entities::transaction::{Transaction, TransactionType};
+use crate::parser::{ClientKey, Store, TransactionHash};
+
+/// Applied deposits and withdrawals of `history` in tx order, each run of consecutive deposits
+/// of a client coalesced into a single deposit: the first one of the run, carrying their sum.
+/// Other clients' transactions don't break a run, a withdrawal of the client does.
+pub fn coalesce_deposits(history: &TransactionHash) -> Vec<Transaction> {
+    let mut transactions = history.entries().map(|(_, t)| t).collect::<Vec<_>>();
+    transactions.sort_by_key(|transaction| transaction.tx);
+
+    let mut coalesced: Vec<Transaction> = Vec::with_capacity(transactions.len());
+    // Index in `coalesced` of the deposit run each client has open
+    let mut runs = HashMap::<ClientKey, usize>::new();
+    for transaction in transactions {
+        let key = (transaction.client, transaction.currency.clone());
+        match transaction.r#type {
+            TransactionType::Deposit => match runs.get(&key) {
+                Some(&run) => {
+                    let deposit = &mut coalesced[run];
+                    deposit.amount = deposit
+                        .amount
+                        .zip(transaction.amount)
+                        .map(|(sum, amount)| sum.saturating_add(amount));
+                }
+                None => {
+                    runs.insert(key, coalesced.len());
+                    coalesced.push(transaction);
+                }
+            },
+            _ => {
+                runs.remove(&key);
+                coalesced.push(transaction);
+            }
+        }
+    }
+    coalesced
+}
+
+/// Writes `transactions` as CSV in the input format into `output`
+pub async fn write_transactions<W>(transactions: &[Transaction], output: W) -> anyhow::Result<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    let with_currency = transactions.iter().any(|t| t.currency.is_some());
+    let mut headers = vec!["type", "client", "tx", "amount"];
+    if with_currency {
+        headers.push("currency");
+    }
+
+    let mut wtr = csv_async::AsyncWriter::from_writer(output);
+    wtr.write_record(headers).await?;
+    for transaction in transactions {
+        let mut record = vec![
+            transaction.r#type.name().to_string(),
+            transaction.client.to_string(),
+            transaction.tx.to_string(),
+            transaction
+                .amount
+                .map(Client::format_amount)
+                .unwrap_or_default(),
+        ];
+        if with_currency {
+            record.push(transaction.currency.clone().unwrap_or_default());
+        }
+        wtr.write_record(record).await?;
+    }
+    Ok(wtr.into_inner().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use std::io;
+
+    use crate::options::Options;
+    use crate::parser::{parse_into_engine, parse_transactions};
+
+    #[tokio::test]
+    async fn test_coalesced_sums_match_totals() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,5.0
+deposit,1,3,2.5
+widthdrawal,1,4,3.0
+deposit,1,5,0.25
+deposit,1,6,0.75
+widthdrawal,2,7,9.0
+deposit,2,8,1.0
+";
+        let options = Options::default();
+        let engine = parse_into_engine(input, &options, &mut io::sink()).await?;
+        let coalesced = coalesce_deposits(engine.past_transactions());
+        let output = String::from_utf8(write_transactions(&coalesced, vec![]).await?)?;
+        assert_that!(output.as_str()).is_equal_to(
+            "type,client,tx,amount
+deposit,1,1,3.5
+deposit,2,2,6
+widthdrawal,1,4,3
+deposit,1,5,1
+",
+        );
+
+        // Replaying the coalesced transactions ends with the same totals
+        let replayed = parse_transactions(output.as_bytes(), &options, &mut io::sink()).await?;
+        for (key, client) in engine.clients() {
+            assert_that!(replayed[key].total).is_equal_to(client.total);
+        }
+
+        Ok(())
+    }
+}
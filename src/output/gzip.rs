@@ -0,0 +1,73 @@
+#[cfg(feature = "sqlite")]
+use anyhow::bail;
+use async_compression::tokio::write::GzipEncoder;
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::OutputSink;
+use crate::options::Options;
+use crate::parser::ClientHash;
+
+/// Writes `clients` as gzipped CSV to the `options.output` file or STDOUT
+pub async fn write_clients(clients: &ClientHash, options: &Options) -> anyhow::Result<()> {
+    match &options.output {
+        OutputSink::Stdout => {
+            write_compressed(clients, options, tokio::io::stdout()).await?;
+        }
+        OutputSink::File(path) => {
+            write_compressed(clients, options, File::create(path).await?).await?;
+        }
+        #[cfg(feature = "sqlite")]
+        OutputSink::Sqlite(_) => bail!("--gzip-output only writes CSV"),
+    }
+    Ok(())
+}
+
+/// Writes `clients` as gzipped CSV into `output`, finishing the gzip stream
+async fn write_compressed<W>(
+    clients: &ClientHash,
+    options: &Options,
+    output: W,
+) -> anyhow::Result<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut encoder =
+        crate::parser::write_clients(clients, options, GzipEncoder::new(output)).await?;
+    encoder.shutdown().await?;
+    Ok(encoder.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use async_compression::tokio::bufread::GzipDecoder;
+    use std::io;
+    use tokio::io::AsyncReadExt;
+
+    use crate::parser::parse_transactions;
+
+    #[tokio::test]
+    async fn test_write_compressed() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,2,1,2.5
+deposit,1,2,1.0
+widthdrawal,2,3,0.5
+";
+        let options = Options::default();
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+        let compressed = write_compressed(&clients, &options, vec![]).await?;
+        assert!(compressed.starts_with(&[0x1f, 0x8b]));
+
+        let mut decompressed = String::new();
+        GzipDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .await?;
+        assert_that!(decompressed).is_equal_to(
+            "client,available,held,total,locked\n1,1,0,1,false\n2,2,0,2,false\n".to_string(),
+        );
+
+        Ok(())
+    }
+}
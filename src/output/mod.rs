@@ -0,0 +1,128 @@
+pub mod coalesced;
+#[cfg(feature = "gzip")]
+pub mod gzip;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use anyhow::{bail, Context};
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where the resulting clients are written
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum OutputSink {
+    /// CSV on STDOUT, given as `-`
+    #[default]
+    Stdout,
+    /// CSV file
+    File(PathBuf),
+    /// `clients` table of a SQLite database, given as `sqlite://path.db`
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+}
+
+impl OutputSink {
+    /// Fails if the output can't be written, e.g. its directory doesn't exist, so a run can stop
+    /// before processing its input rather than once it's done
+    pub fn check_writable(&self) -> anyhow::Result<()> {
+        match self {
+            OutputSink::Stdout => Ok(()),
+            OutputSink::File(path) => check_file_writable(path),
+            #[cfg(feature = "sqlite")]
+            OutputSink::Sqlite(path) => check_file_writable(path),
+        }
+    }
+
+    /// Whether the output is a file to gzip, its path ending in `.gz`
+    pub fn is_gzip(&self) -> bool {
+        match self {
+            OutputSink::File(path) => path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("gz")),
+            _ => false,
+        }
+    }
+}
+
+/// Opens `path` for writing without truncating it, removing it again if it didn't exist
+fn check_file_writable(path: &Path) -> anyhow::Result<()> {
+    let existed = path.exists();
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Can't write the output to {}", path.display()))?;
+    if !existed {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+impl FromStr for OutputSink {
+    type Err = anyhow::Error;
+
+    fn from_str(output: &str) -> Result<Self, Self::Err> {
+        if output == "-" {
+            Ok(OutputSink::Stdout)
+        } else if let Some(path) = output.strip_prefix("sqlite://") {
+            #[cfg(feature = "sqlite")]
+            return Ok(OutputSink::Sqlite(PathBuf::from(path)));
+            #[cfg(not(feature = "sqlite"))]
+            bail!(
+                "Can't write to {}, SQLite support requires the sqlite feature",
+                path
+            );
+        } else if output.is_empty() {
+            bail!("Empty output");
+        } else {
+            Ok(OutputSink::File(PathBuf::from(output)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+
+    #[test]
+    fn test_parse_output_sink() -> anyhow::Result<()> {
+        assert_that!("-".parse::<OutputSink>()?).is_equal_to(OutputSink::Stdout);
+        assert_that!("out.csv".parse::<OutputSink>()?)
+            .is_equal_to(OutputSink::File(PathBuf::from("out.csv")));
+        #[cfg(feature = "sqlite")]
+        assert_that!("sqlite://out.db".parse::<OutputSink>()?)
+            .is_equal_to(OutputSink::Sqlite(PathBuf::from("out.db")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_gzip() -> anyhow::Result<()> {
+        assert!("out.csv.gz".parse::<OutputSink>()?.is_gzip());
+        assert!("OUT.GZ".parse::<OutputSink>()?.is_gzip());
+        assert!(!"out.csv".parse::<OutputSink>()?.is_gzip());
+        assert!(!OutputSink::Stdout.is_gzip());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_writable() -> anyhow::Result<()> {
+        OutputSink::Stdout.check_writable()?;
+        let path = std::env::temp_dir().join(format!("ex-writable-{}.csv", std::process::id()));
+        OutputSink::File(path.clone()).check_writable()?;
+        // Left as it was
+        assert!(!path.exists());
+
+        let missing = std::env::temp_dir().join("ex-missing-dir").join("out.csv");
+        let error = OutputSink::File(missing.clone())
+            .check_writable()
+            .unwrap_err();
+        assert_that!(error.to_string())
+            .is_equal_to(format!("Can't write the output to {}", missing.display()));
+        Ok(())
+    }
+}
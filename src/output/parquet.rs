@@ -0,0 +1,162 @@
+use arrow_array::{ArrayRef, BooleanArray, RecordBatch, StringArray, UInt16Array};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::entities::client::Client;
+use crate::options::Options;
+use crate::parser::ClientHash;
+
+/// Writes `clients` as a Parquet file at `path`, sorted by client id then currency. Amounts are
+/// stored as strings, rounded to the scale of the client's currency as in the CSV output.
+pub fn write_clients(clients: &ClientHash, options: &Options, path: &Path) -> anyhow::Result<()> {
+    let mut clients = clients
+        .values()
+        .filter(|client| options.outputs(client))
+        .map(|client| options.output_client(client))
+        .collect::<Vec<_>>();
+    clients.sort_by(|a, b| (a.id, &a.currency).cmp(&(b.id, &b.currency)));
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", DataType::Utf8, false),
+        Field::new("held", DataType::Utf8, false),
+        Field::new("total", DataType::Utf8, false),
+        Field::new("locked", DataType::Boolean, false),
+        Field::new("currency", DataType::Utf8, true),
+    ]));
+    let amounts = |amount: fn(&Client) -> _| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(
+            clients
+                .iter()
+                .map(|client| Client::format_amount(amount(client))),
+        ))
+    };
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(UInt16Array::from_iter_values(
+                clients.iter().map(|client| client.id.0),
+            )),
+            amounts(|client| client.available),
+            amounts(|client| client.held),
+            amounts(|client| client.total),
+            Arc::new(BooleanArray::from_iter(
+                clients.iter().map(|client| Some(client.locked)),
+            )),
+            Arc::new(StringArray::from_iter(
+                clients.iter().map(|client| client.currency.as_deref()),
+            )),
+        ],
+    )?;
+
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::UInt16Type;
+    use assertor::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::io;
+
+    use crate::parser::parse_transactions;
+
+    #[tokio::test]
+    async fn test_parquet_read_back() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,2,1,2.0
+deposit,1,2,1.5
+widthdrawal,1,3,0.25
+dispute,2,1,
+chargeback,2,1,
+";
+        let options = Options::default();
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+
+        let path = std::env::temp_dir().join(format!("ex-test-{}.parquet", std::process::id()));
+        write_clients(&clients, &options, &path)?;
+
+        let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(&path)?)?
+            .build()?
+            .collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        assert_that!(batches).has_length(1);
+
+        let batch = &batches[0];
+        let strings = |column: &str| -> Vec<String> {
+            batch
+                .column_by_name(column)
+                .unwrap()
+                .as_string::<i32>()
+                .iter()
+                .map(|value| value.unwrap_or_default().to_string())
+                .collect()
+        };
+        let ids = batch
+            .column_by_name("client")
+            .unwrap()
+            .as_primitive::<UInt16Type>()
+            .values()
+            .to_vec();
+        let locked = batch
+            .column_by_name("locked")
+            .unwrap()
+            .as_boolean()
+            .iter()
+            .collect::<Vec<_>>();
+        assert_that!(ids).is_equal_to(vec![1, 2]);
+        assert_that!(strings("available")).is_equal_to(vec!["1.25".to_string(), "0".to_string()]);
+        assert_that!(strings("held")).is_equal_to(vec!["0".to_string(), "0".to_string()]);
+        assert_that!(strings("total")).is_equal_to(vec!["1.25".to_string(), "0".to_string()]);
+        assert_that!(locked).is_equal_to(vec![Some(false), Some(true)]);
+        assert_that!(batch.column_by_name("currency").unwrap().null_count()).is_equal_to(2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parquet_rounded_to_currency_scale() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount,currency
+deposit,1,1,10.456,USD
+deposit,2,2,1234.6,JPY
+";
+        let options = Options::default();
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+
+        let path =
+            std::env::temp_dir().join(format!("ex-test-scale-{}.parquet", std::process::id()));
+        write_clients(&clients, &options, &path)?;
+
+        let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(&path)?)?
+            .build()?
+            .collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        assert_that!(batches).has_length(1);
+
+        let batch = &batches[0];
+        let strings = |column: &str| -> Vec<String> {
+            batch
+                .column_by_name(column)
+                .unwrap()
+                .as_string::<i32>()
+                .iter()
+                .map(|value| value.unwrap_or_default().to_string())
+                .collect()
+        };
+        assert_that!(strings("currency")).is_equal_to(vec!["USD".to_string(), "JPY".to_string()]);
+        assert_that!(strings("available"))
+            .is_equal_to(vec!["10.46".to_string(), "1235".to_string()]);
+        assert_that!(strings("total")).is_equal_to(vec!["10.46".to_string(), "1235".to_string()]);
+
+        Ok(())
+    }
+}
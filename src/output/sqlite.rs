@@ -0,0 +1,136 @@
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+use crate::entities::client::Client;
+use crate::options::Options;
+use crate::parser::ClientHash;
+
+/// Writes `clients` into the `clients` table of the SQLite database at `path`, creating it if
+/// needed. Amounts are stored as text, rounded to the scale of the client's currency as in the
+/// CSV output, rows of clients already present are replaced.
+pub fn write_clients(clients: &ClientHash, options: &Options, path: &Path) -> anyhow::Result<()> {
+    let mut connection = Connection::open(path)?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS clients (
+            client INTEGER NOT NULL,
+            available TEXT NOT NULL,
+            held TEXT NOT NULL,
+            total TEXT NOT NULL,
+            locked BOOLEAN NOT NULL,
+            currency TEXT NOT NULL DEFAULT '',
+            PRIMARY KEY (client, currency)
+        )",
+        [],
+    )?;
+
+    let transaction = connection.transaction()?;
+    {
+        let mut insert = transaction.prepare(
+            "INSERT OR REPLACE INTO clients (client, available, held, total, locked, currency)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for client in clients.values().filter(|client| options.outputs(client)) {
+            let client = options.output_client(client);
+            insert.execute(params![
+                client.id.0,
+                Client::format_amount(client.available),
+                Client::format_amount(client.held),
+                Client::format_amount(client.total),
+                client.locked,
+                client.currency.clone().unwrap_or_default(),
+            ])?;
+        }
+    }
+    transaction.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use std::io;
+
+    use crate::parser::{parse_transactions, write_clients as write_csv};
+
+    #[tokio::test]
+    async fn test_sqlite_matches_csv() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.5
+deposit,2,2,2.0
+deposit,3,3,3.25
+widthdrawal,3,4,1.0
+dispute,2,2,
+chargeback,2,2,
+";
+        let options = Options::default();
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+
+        let path = std::env::temp_dir().join(format!("ex-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        write_clients(&clients, &options, &path)?;
+
+        let connection = Connection::open(&path)?;
+        let mut select = connection.prepare(
+            "SELECT client, available, held, total, locked FROM clients ORDER BY client",
+        )?;
+        let mut rows = vec!["client,available,held,total,locked".to_string()];
+        for row in select.query_map([], |row| {
+            Ok(format!(
+                "{},{},{},{},{}",
+                row.get::<_, u16>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, bool>(4)?
+            ))
+        })? {
+            rows.push(row?);
+        }
+        std::fs::remove_file(&path)?;
+
+        let csv = String::from_utf8(write_csv(&clients, &options, vec![]).await?)?;
+        assert_that!(rows).is_equal_to(csv.lines().map(String::from).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_rounded_to_currency_scale() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount,currency
+deposit,1,1,10.456,USD
+deposit,2,2,1234.6,JPY
+";
+        let options = Options::default();
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+
+        let path = std::env::temp_dir().join(format!("ex-test-scale-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        write_clients(&clients, &options, &path)?;
+
+        let connection = Connection::open(&path)?;
+        let mut select = connection
+            .prepare("SELECT currency, available, held, total FROM clients ORDER BY client")?;
+        let mut rows = vec![];
+        for row in select.query_map([], |row| {
+            Ok(format!(
+                "{},{},{},{}",
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?
+            ))
+        })? {
+            rows.push(row?);
+        }
+        std::fs::remove_file(&path)?;
+
+        assert_that!(rows).is_equal_to(vec![
+            "USD,10.46,0,10.46".to_string(),
+            "JPY,1235,0,1235".to_string(),
+        ]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use csv_async::{AsyncDeserializer, ByteRecord};
+use std::io::Write;
+use tokio::io::AsyncRead;
+
+use super::parser::{amount_column, normalize_amount, normalize_type, process_row, type_column};
+use super::processor::Processor;
+use crate::entities::transaction::Transaction;
+use crate::options::Options;
+
+/// Reads up to `options.batch_size` raw records into a reused buffer, then deserializes and
+/// applies them in a tight loop, leaving a single await point per record read instead of the
+/// stream's per-row polling. Rows are applied in their original order, their types being
+/// normalized first with `options.normalize_types` and their amounts with
+/// `options.currency_symbol` and `options.decimal_separator`.
+pub(super) async fn parse_records<R, T>(
+    rdr: &mut AsyncDeserializer<R>,
+    processor: &mut Processor<'_, T>,
+    options: &Options,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let headers = rdr.byte_headers().await?.clone();
+    let column = type_column(&headers, options);
+    let amount = amount_column(&headers, options);
+    let mut batch = vec![ByteRecord::new(); options.batch_size.max(1)];
+    let mut done = false;
+
+    while !done {
+        let mut len = 0;
+        let mut error = None;
+        while len < batch.len() {
+            match rdr.read_byte_record(&mut batch[len]).await {
+                Ok(true) => len += 1,
+                Ok(false) => {
+                    done = true;
+                    break;
+                }
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                // Reported after the records read before it
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        for record in &mut batch[..len] {
+            if processor.interrupted() {
+                return Ok(());
+            }
+            if let Some(column) = column {
+                normalize_type(record, column);
+            }
+            if let Some(amount) = amount {
+                normalize_amount(record, amount, options);
+            }
+            process_row(processor, record.deserialize::<Transaction>(Some(&headers)))?;
+        }
+        if let Some(e) = error {
+            if processor.interrupted() {
+                return Ok(());
+            }
+            processor.skip(e)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1194 @@
+use anyhow::bail;
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::parser::{client_key, parse_single_transaction, ClientHash, ClientKey, TransactionHash};
+use super::stats::{RunStats, Summary};
+use super::store::Store;
+use super::Notice;
+use crate::entities::client::Client;
+use crate::entities::id::{ClientId, TxId};
+use crate::entities::transaction::{Outcome, Reason, Transaction, TransactionType};
+use crate::options::{AmountUnit, OpenDisputes, Options};
+
+/// A row of a previous output, see [`Engine::load_clients`]
+#[derive(Deserialize)]
+struct ClientRow {
+    client: ClientId,
+    #[serde(deserialize_with = "deserialize_balance")]
+    available: Decimal,
+    #[serde(deserialize_with = "deserialize_balance")]
+    held: Decimal,
+    #[serde(deserialize_with = "deserialize_balance")]
+    total: Decimal,
+    locked: bool,
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+/// Parses a balance from its textual representation so no precision is lost, the csv crate
+/// would otherwise read it as a float
+fn deserialize_balance<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let balance = String::deserialize(deserializer)?;
+    Decimal::from_str(&balance)
+        .map_err(|e| D::Error::custom(format!("invalid balance {}: {}", balance, e)))
+}
+
+/// Internal maps of an engine, see [`Engine::dump_maps`]
+#[derive(Serialize, Deserialize)]
+struct Dump {
+    clients: Vec<Client>,
+    past_transactions: Vec<Transaction>,
+    disputed_transactions: Vec<Transaction>,
+}
+
+/// Callback of [`Engine::with_hook`]
+type HookFn = dyn Fn(&Transaction, &Client) + Send + Sync;
+
+pub(crate) struct Hook(Box<HookFn>);
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// Holds the clients and the transactions history, applying transactions one by one.
+/// Kept in memory by default, see [`Engine::with_stores`] to plug another [`Store`].
+#[derive(Default, Debug)]
+pub struct Engine<C = ClientHash, T = TransactionHash> {
+    pub(crate) clients: C,
+    pub(crate) past_transactions: T,
+    pub(crate) disputed_transactions: T,
+    pub(crate) options: Options,
+    /// Latest timestamp seen, checked with `--require-monotonic`
+    pub(crate) last_timestamp: Option<u64>,
+    pub(crate) stats: RunStats,
+    /// Write-ahead log, see [`Engine::with_wal`]
+    pub(crate) wal: Option<csv::Writer<File>>,
+    /// Called after each applied transaction, see [`Engine::with_hook`]
+    pub(crate) hook: Option<Hook>,
+    /// Notices of the last applied transaction, kept for the processor to log them with their
+    /// row when `collect_notices` is set, written on STDERR otherwise
+    pub(crate) notices: Vec<Notice>,
+    pub(crate) collect_notices: bool,
+}
+
+impl Engine {
+    /// Engine applying transactions according to `options`
+    pub fn new(options: Options) -> Self {
+        Engine {
+            options,
+            ..Default::default()
+        }
+    }
+}
+
+impl<C, T> Engine<C, T>
+where
+    C: Store<ClientKey, Client>,
+    T: Store<TxId, Transaction>,
+{
+    /// Engine keeping its clients and transactions history in the given stores
+    pub fn with_stores(
+        clients: C,
+        past_transactions: T,
+        disputed_transactions: T,
+        options: Options,
+    ) -> Self {
+        Engine {
+            clients,
+            past_transactions,
+            disputed_transactions,
+            options,
+            last_timestamp: None,
+            stats: RunStats::default(),
+            wal: None,
+            hook: None,
+            notices: vec![],
+            collect_notices: false,
+        }
+    }
+
+    /// Logs every transaction to the write-ahead log at `path` before applying it. The
+    /// transactions already logged there are replayed first, so an engine dropped in the middle
+    /// of a run, e.g. by a crash, is recovered by calling this again with the same `path`.
+    /// Replayed transactions don't call the hook, if one was set before, as they went through it
+    /// before the crash.
+    pub fn with_wal(mut self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let mut rdr = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_path(path)?;
+            let hook = self.hook.take();
+            for transaction in rdr.deserialize::<Transaction>() {
+                self.apply(&transaction?)?;
+            }
+            self.hook = hook;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.wal = Some(
+            csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(file),
+        );
+        Ok(self)
+    }
+
+    /// Calls `hook` after each applied transaction with the resulting state of its client, e.g.
+    /// to send notifications, run fraud checks or stream updates. Rejected transactions don't
+    /// call it, the resolves applied by [`Engine::finalize`] do. Replaces any previous hook.
+    pub fn with_hook(
+        mut self,
+        hook: impl Fn(&Transaction, &Client) + Send + Sync + 'static,
+    ) -> Self {
+        self.hook = Some(Hook(Box::new(hook)));
+        self
+    }
+
+    /// Applies a single transaction, returning whether it went through
+    pub fn apply(&mut self, transaction: &Transaction) -> anyhow::Result<Outcome> {
+        if let Some(wal) = &mut self.wal {
+            wal.serialize(transaction)?;
+            wal.flush()?;
+        }
+        let outcome = self.try_apply(transaction)?;
+        self.stats.count(&outcome);
+        self.call_hook(transaction, &outcome);
+        Ok(outcome)
+    }
+
+    fn call_hook(&self, transaction: &Transaction, outcome: &Outcome) {
+        if let (Some(hook), true) = (&self.hook, outcome.is_applied()) {
+            if let Some(client) = self.client(transaction) {
+                (hook.0)(transaction, &client);
+            }
+        }
+    }
+
+    fn try_apply(&mut self, transaction: &Transaction) -> anyhow::Result<Outcome> {
+        if self.options.require_monotonic {
+            if let Some(timestamp) = transaction.timestamp {
+                if let Some(last_timestamp) = self.last_timestamp.filter(|last| timestamp < *last) {
+                    diagnostic!(
+                        self.options,
+                        "Can't apply tx {}, timestamp {} is before {}",
+                        transaction.tx,
+                        timestamp,
+                        last_timestamp
+                    );
+                    return Ok(Outcome::Rejected(Reason::OutOfOrder));
+                }
+                self.last_timestamp = Some(timestamp);
+            }
+        }
+
+        let before = match transaction.r#type {
+            TransactionType::Dispute if self.options.verify_invariants => self.client(transaction),
+            _ => None,
+        };
+        let outcome = parse_single_transaction(
+            transaction,
+            &mut self.clients,
+            &mut self.past_transactions,
+            &mut self.disputed_transactions,
+            &self.options,
+            &mut self.notices,
+        )?;
+        if !self.collect_notices {
+            for notice in self.notices.drain(..) {
+                notice.write(&self.options, &mut io::stderr())?;
+            }
+        }
+        if self.options.verify_invariants && outcome.is_applied() {
+            match (&transaction.r#type, before, self.client(transaction)) {
+                (TransactionType::Dispute, Some(before), Some(after)) => {
+                    // A disputed withdrawal holds back the withdrawn amount, raising the total
+                    let raised = match self.transaction(transaction.tx) {
+                        Some(disputed) if disputed.r#type == TransactionType::Widthdrawal => {
+                            disputed.amount.unwrap_or_default()
+                        }
+                        _ => Decimal::ZERO,
+                    };
+                    check_dispute_total(transaction.tx, &before, &after, raised)?
+                }
+                (TransactionType::Widthdrawal, _, Some(after)) => {
+                    let tolerance = self
+                        .options
+                        .deny_withdrawal_overdraft_epsilon
+                        .unwrap_or_default();
+                    check_withdrawal_total(transaction.tx, &after, tolerance)?
+                }
+                _ => {}
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Deserializes then applies a raw CSV record, for callers already using the `csv` crate.
+    /// Fields are expected in the input order: `type,client,tx,amount[,currency,timestamp]`.
+    pub fn apply_record(&mut self, record: &ByteRecord) -> anyhow::Result<(Transaction, Outcome)> {
+        let mut record = record.clone();
+        record.trim();
+        let mut transaction = record.deserialize::<Transaction>(None)?;
+        if let TransactionType::Custom(name) = &transaction.r#type {
+            if !self.options.handlers.contains(name) {
+                bail!("unknown transaction type {}", name);
+            }
+        }
+        if self.options.amount_unit == AmountUnit::Cents {
+            transaction.amount_from_cents()?;
+        }
+        let outcome = self.apply(&transaction)?;
+        Ok((transaction, outcome))
+    }
+
+    /// Client affected by `transaction`, if it exists
+    pub fn client(&self, transaction: &Transaction) -> Option<Client> {
+        self.clients
+            .get(&client_key(transaction, &self.past_transactions))
+    }
+
+    /// Deposit or withdrawal `tx` from the transactions history, if it went through
+    pub fn transaction(&self, tx: TxId) -> Option<Transaction> {
+        self.past_transactions.get(&tx)
+    }
+
+    /// Whether `tx` is under dispute, waiting for a resolve or a chargeback
+    pub fn is_disputed(&self, tx: TxId) -> bool {
+        self.disputed_transactions.contains_key(&tx)
+    }
+
+    /// All clients seen so far
+    pub fn clients(&self) -> &C {
+        &self.clients
+    }
+
+    /// Applied deposits and withdrawals, by tx id
+    pub fn past_transactions(&self) -> &T {
+        &self.past_transactions
+    }
+
+    pub fn into_clients(self) -> C {
+        self.clients
+    }
+
+    /// What happened to the rows applied so far
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    /// Clients whose available funds are negative, ordered by id and currency
+    pub fn negative_available(&self) -> Vec<Client> {
+        let mut clients = self
+            .clients
+            .entries()
+            .filter(|(_, client)| client.available < Decimal::ZERO)
+            .collect::<Vec<_>>();
+        clients.sort_by(|(a, _), (b, _)| a.cmp(b));
+        clients.into_iter().map(|(_, client)| client).collect()
+    }
+
+    /// Summary of the clients and of the run so far
+    pub fn summary(&self) -> Summary {
+        let mut negative_available = self
+            .negative_available()
+            .into_iter()
+            .map(|client| client.id)
+            .collect::<Vec<_>>();
+        negative_available.dedup();
+        let mut summary = Summary {
+            clients: self.clients.len(),
+            locked: 0,
+            held: Decimal::ZERO,
+            negative_available,
+            stats: self.stats.clone(),
+        };
+        for (_, client) in self.clients.entries() {
+            if client.locked {
+                summary.locked += 1;
+            }
+            summary.held = summary.held.saturating_add(client.held);
+        }
+        summary.held = summary.held.normalize();
+        summary
+    }
+
+    /// Merges the clients and transactions history of `other`, an engine which ran on a
+    /// distinct set of clients, e.g. another shard of the input. Balances aren't combined:
+    /// a client, or a transaction, known by both engines is a conflict and nothing is merged.
+    pub fn merge(&mut self, other: Engine<C, T>) -> anyhow::Result<()> {
+        if let Some((key, _)) = other
+            .clients
+            .entries()
+            .find(|(key, _)| self.clients.contains_key(key))
+        {
+            bail!("Client {} was processed by both engines", key.0);
+        }
+        if let Some((tx, _)) = other
+            .past_transactions
+            .entries()
+            .chain(other.disputed_transactions.entries())
+            .find(|(tx, _)| {
+                self.past_transactions.contains_key(tx)
+                    || self.disputed_transactions.contains_key(tx)
+            })
+        {
+            bail!("Tx {} was processed by both engines", tx);
+        }
+
+        for (key, client) in other.clients.entries() {
+            self.clients.insert(key, client);
+        }
+        for (tx, transaction) in other.past_transactions.entries() {
+            self.past_transactions.insert(tx, transaction);
+        }
+        for (tx, transaction) in other.disputed_transactions.entries() {
+            self.disputed_transactions.insert(tx, transaction);
+        }
+        self.stats += &other.stats;
+        Ok(())
+    }
+
+    /// Loads the clients of a previous output as the starting state, so a file of new
+    /// transactions can be applied as a delta without reprocessing the history. That history
+    /// isn't known though: disputes of earlier transactions are rejected as unknown, and funds
+    /// already held can't be resolved nor charged back.
+    pub fn load_clients<R: Read>(&mut self, reader: R) -> anyhow::Result<()> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in rdr.deserialize::<ClientRow>() {
+            let row = row?;
+            if row.available + row.held != row.total {
+                bail!(
+                    "Client {} total {} isn't available {} + held {}",
+                    row.client,
+                    row.total,
+                    row.available,
+                    row.held
+                );
+            }
+            self.clients.insert(
+                (row.client, row.currency.clone()),
+                Client {
+                    id: row.client,
+                    available: row.available,
+                    held: row.held,
+                    total: row.total,
+                    locked: row.locked,
+                    currency: row.currency,
+                    active: true,
+                    ..Default::default()
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes the clients, transactions history and disputed transactions as JSON to `writer`,
+    /// sorted by key, for debugging
+    pub fn dump_maps<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut clients = self.clients.entries().collect::<Vec<_>>();
+        clients.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let sorted = |store: &T| {
+            let mut transactions = store.entries().collect::<Vec<_>>();
+            transactions.sort_by_key(|(tx, _)| *tx);
+            transactions
+                .into_iter()
+                .map(|(_, transaction)| transaction)
+                .collect()
+        };
+        let dump = Dump {
+            clients: clients.into_iter().map(|(_, client)| client).collect(),
+            past_transactions: sorted(&self.past_transactions),
+            disputed_transactions: sorted(&self.disputed_transactions),
+        };
+        serde_json::to_writer_pretty(writer, &dump)?;
+        Ok(())
+    }
+
+    /// Restores the clients, transactions history and disputed transactions written by
+    /// [`Engine::dump_maps`] from `reader`. Restored clients are considered active.
+    pub fn load_maps<R: Read>(&mut self, reader: R) -> anyhow::Result<()> {
+        let dump: Dump = serde_json::from_reader(reader)?;
+        for mut client in dump.clients {
+            client.active = true;
+            self.clients
+                .insert((client.id, client.currency.clone()), client);
+        }
+        for transaction in dump.past_transactions {
+            self.past_transactions.insert(transaction.tx, transaction);
+        }
+        for transaction in dump.disputed_transactions {
+            self.disputed_transactions
+                .insert(transaction.tx, transaction);
+        }
+        Ok(())
+    }
+
+    /// Ends the stream: returns the disputes still open, ordered by tx, resolving them with
+    /// `--open-disputes resolve`. Those resolves are applied as any other transaction: logged
+    /// to the write-ahead log, counted in the stats and passed to the hook.
+    pub fn finalize(&mut self) -> anyhow::Result<Vec<Transaction>> {
+        let mut open = self
+            .disputed_transactions
+            .entries()
+            .map(|(_, transaction)| transaction)
+            .collect::<Vec<_>>();
+        open.sort_by_key(|transaction| transaction.tx);
+
+        if self.options.open_disputes == OpenDisputes::Resolve {
+            for disputed in &open {
+                let resolve = Transaction {
+                    r#type: TransactionType::Resolve,
+                    client: disputed.client,
+                    tx: disputed.tx,
+                    currency: disputed.currency.clone(),
+                    ..Default::default()
+                };
+                self.apply(&resolve)?;
+            }
+        }
+        Ok(open)
+    }
+
+    /// Checks that every client is consistent: `total == available + held` and `held >= 0`,
+    /// and that every disputed transaction is part of the transactions history
+    pub fn check_invariants(&self) -> anyhow::Result<()> {
+        if let Some((tx, _)) = self
+            .disputed_transactions
+            .entries()
+            .find(|(tx, _)| !self.past_transactions.contains_key(tx))
+        {
+            bail!("Disputed tx {} isn't in the transactions history", tx);
+        }
+
+        for (_, client) in self.clients.entries() {
+            if client.available + client.held != client.total {
+                bail!(
+                    "Client {} total {} isn't available {} + held {}",
+                    client.id,
+                    client.total,
+                    client.available,
+                    client.held
+                );
+            }
+            if client.held.is_sign_negative() && !client.held.is_zero() {
+                bail!("Client {} has negative held {}", client.id, client.held);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A dispute of a deposit only moves funds from available to held, the total of the client must
+/// not change. A dispute of a withdrawal holds the withdrawn amount back, the total must then be
+/// `raised` by exactly that amount.
+fn check_dispute_total(
+    tx: TxId,
+    before: &Client,
+    after: &Client,
+    raised: Decimal,
+) -> anyhow::Result<()> {
+    let expected = before.total.checked_add(raised);
+    if expected != Some(after.total) {
+        bail!(
+            "Dispute of tx {} changed client {} total from {} to {}",
+            tx,
+            after.id,
+            before.total,
+            after.total
+        );
+    }
+    Ok(())
+}
+
+/// A withdrawal is gated on available funds, held ones being left aside, so it can't take the
+/// total of the client below zero, but for the overdraft `tolerance`
+fn check_withdrawal_total(tx: TxId, after: &Client, tolerance: Decimal) -> anyhow::Result<()> {
+    if after.total < -tolerance {
+        bail!(
+            "Withdrawal of tx {} made client {} total negative: {}",
+            tx,
+            after.id,
+            after.total
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::AccountPolicy;
+    use crate::parser::write_clients;
+    use assertor::*;
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Alternate store counting its writes, standing in for a disk-backed one
+    #[derive(Default)]
+    struct CountingStore<K, V> {
+        entries: BTreeMap<K, V>,
+        writes: usize,
+    }
+
+    impl<K: Ord + Clone, V: Clone> Store<K, V> for CountingStore<K, V> {
+        fn get(&self, key: &K) -> Option<V> {
+            self.entries.get(key).cloned()
+        }
+
+        fn insert(&mut self, key: K, value: V) {
+            self.writes += 1;
+            self.entries.insert(key, value);
+        }
+
+        fn remove(&mut self, key: &K) -> Option<V> {
+            self.writes += 1;
+            self.entries.remove(key)
+        }
+
+        fn len(&self) -> usize {
+            self.entries.len()
+        }
+
+        fn entries(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+            Box::new(self.entries.clone().into_iter())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_record() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Options::default());
+
+        let (transaction, outcome) =
+            engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "1", "3.5"]))?;
+        assert_that!(transaction.tx).is_equal_to(TxId(1));
+        assert!(outcome.is_applied());
+        let (_, outcome) =
+            engine.apply_record(&ByteRecord::from(vec![" widthdrawal", " 1", " 2", " 1.0"]))?;
+        assert!(outcome.is_applied());
+        let (_, outcome) = engine.apply_record(&ByteRecord::from(vec!["dispute", "1", "1", ""]))?;
+        assert!(!outcome.is_applied());
+
+        assert!(engine
+            .apply_record(&ByteRecord::from(vec!["unknown", "1", "3", "1.0"]))
+            .is_err());
+
+        let client = &engine.clients()[&(ClientId(1), None)];
+        assert_that!(client.available).is_equal_to(dec!(2.5));
+        assert_that!(client.held).is_equal_to(dec!(0));
+        assert_that!(client.total).is_equal_to(dec!(2.5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_invariants() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Options::default());
+        engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "1", "3.5"]))?;
+        engine.check_invariants()?;
+
+        engine.clients.get_mut(&(ClientId(1), None)).unwrap().held += dec!(1);
+        assert!(engine.check_invariants().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_invariants_disputed_not_in_history() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Options::default());
+        engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "1", "3.5"]))?;
+        engine.apply_record(&ByteRecord::from(vec!["dispute", "1", "1", ""]))?;
+        engine.check_invariants()?;
+
+        engine.past_transactions.remove(&TxId(1));
+        assert_that!(engine.check_invariants().unwrap_err().to_string())
+            .is_equal_to("Disputed tx 1 isn't in the transactions history".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_preserves_total() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Options {
+            verify_invariants: true,
+            ..Default::default()
+        });
+        engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "1", "3.5"]))?;
+        engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "2", "1.5"]))?;
+        let before = engine.clients[&(ClientId(1), None)].clone();
+        let (_, outcome) = engine.apply_record(&ByteRecord::from(vec!["dispute", "1", "1", ""]))?;
+        assert_that!(outcome).is_equal_to(Outcome::Applied);
+        let after = engine.clients[&(ClientId(1), None)].clone();
+        assert_that!(after.total).is_equal_to(before.total);
+        check_dispute_total(TxId(1), &before, &after, Decimal::ZERO)?;
+
+        // A dispute which would credit held without debiting available
+        let broken = Client {
+            available: before.available,
+            held: before.held + dec!(3.5),
+            total: before.total + dec!(3.5),
+            ..before.clone()
+        };
+        assert_that!(
+            check_dispute_total(TxId(1), &before, &broken, Decimal::ZERO)
+                .unwrap_err()
+                .to_string()
+        )
+        .is_equal_to("Dispute of tx 1 changed client 1 total from 5.0 to 8.5".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_raises_total() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Options {
+            verify_invariants: true,
+            dispute_withdrawals: true,
+            ..Default::default()
+        });
+        engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "1", "10"]))?;
+        engine.apply_record(&ByteRecord::from(vec!["widthdrawal", "1", "2", "4"]))?;
+        let before = engine.clients[&(ClientId(1), None)].clone();
+        let (_, outcome) = engine.apply_record(&ByteRecord::from(vec!["dispute", "1", "2", ""]))?;
+        assert_that!(outcome).is_equal_to(Outcome::Applied);
+        let after = engine.clients[&(ClientId(1), None)].clone();
+        assert_that!(after.total).is_equal_to(dec!(10));
+        assert_that!(after.held).is_equal_to(dec!(4));
+        engine.check_invariants()?;
+
+        // A withdrawal dispute which would leave the total as is, or raise it by another amount
+        for total in [before.total, before.total + dec!(5)] {
+            let broken = Client {
+                total,
+                ..after.clone()
+            };
+            assert!(check_dispute_total(TxId(2), &before, &broken, dec!(4)).is_err());
+        }
+        check_dispute_total(TxId(2), &before, &after, dec!(4))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_withdrawal_keeps_total_positive() -> anyhow::Result<()> {
+        for account_policy in [AccountPolicy::Strict, AccountPolicy::AllowNegative] {
+            let mut engine = Engine::new(Options {
+                verify_invariants: true,
+                account_policy,
+                ..Default::default()
+            });
+            for (record, applied) in [
+                (vec!["deposit", "1", "1", "10"], true),
+                (vec!["deposit", "1", "2", "5"], true),
+                (vec!["dispute", "1", "1", ""], true),
+                // Only the 5 left available can be withdrawn, the 10 held stay in
+                (vec!["widthdrawal", "1", "3", "6"], false),
+                (vec!["widthdrawal", "1", "4", "5"], true),
+                (vec!["resolve", "1", "1", ""], true),
+                (vec!["widthdrawal", "1", "5", "10"], true),
+            ] {
+                let (_, outcome) = engine.apply_record(&ByteRecord::from(record))?;
+                assert_that!(outcome.is_applied()).is_equal_to(applied);
+            }
+            let client = &engine.clients[&(ClientId(1), None)];
+            assert_that!(client.total).is_equal_to(dec!(0));
+            engine.check_invariants()?;
+        }
+
+        // Spent, then disputed: available is negative and no withdrawal goes through
+        let mut engine = Engine::new(Options {
+            verify_invariants: true,
+            account_policy: AccountPolicy::AllowNegative,
+            ..Default::default()
+        });
+        for record in [
+            vec!["deposit", "1", "1", "10"],
+            vec!["widthdrawal", "1", "2", "10"],
+            vec!["dispute", "1", "1", ""],
+        ] {
+            engine.apply_record(&ByteRecord::from(record))?;
+        }
+        let (_, outcome) =
+            engine.apply_record(&ByteRecord::from(vec!["widthdrawal", "1", "3", "1"]))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::InsufficientFunds));
+
+        let overdrawn = Client {
+            id: ClientId(1),
+            available: dec!(-0.02),
+            total: dec!(-0.02),
+            ..Default::default()
+        };
+        check_withdrawal_total(TxId(3), &overdrawn, dec!(0.05))?;
+        assert_that!(check_withdrawal_total(TxId(3), &overdrawn, Decimal::ZERO)
+            .unwrap_err()
+            .to_string())
+        .is_equal_to("Withdrawal of tx 3 made client 1 total negative: -0.02".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_hook() -> anyhow::Result<()> {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let mut engine = Engine::new(Options::default()).with_hook(move |transaction, client| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((transaction.tx, client.clone()));
+        });
+        for record in [
+            vec!["deposit", "1", "1", "3.0"],
+            vec!["widthdrawal", "1", "2", "5.0"],
+            vec!["dispute", "1", "1", ""],
+            vec!["resolve", "1", "9", ""],
+        ] {
+            engine.apply_record(&ByteRecord::from(record))?;
+        }
+
+        // Rejected transactions don't call it
+        let calls = calls.lock().unwrap();
+        assert_that!(*calls).has_length(2);
+        let (tx, client) = &calls[0];
+        assert_that!(*tx).is_equal_to(TxId(1));
+        assert_that!(client.available).is_equal_to(dec!(3.0));
+        let (tx, client) = &calls[1];
+        assert_that!(*tx).is_equal_to(TxId(1));
+        assert_that!(client.available).is_equal_to(dec!(0));
+        assert_that!(client.held).is_equal_to(dec!(3.0));
+        assert_that!(client.id).is_equal_to(ClientId(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hook_on_finalize() -> anyhow::Result<()> {
+        let options = Options {
+            open_disputes: OpenDisputes::Resolve,
+            ..Default::default()
+        };
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let mut engine = Engine::new(options).with_hook(move |transaction, client| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((transaction.r#type.clone(), client.clone()));
+        });
+        engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "1", "3.0"]))?;
+        engine.apply_record(&ByteRecord::from(vec!["dispute", "1", "1", ""]))?;
+        engine.finalize()?;
+
+        let calls = calls.lock().unwrap();
+        assert_that!(*calls).has_length(3);
+        let (r#type, client) = &calls[2];
+        assert_that!(*r#type).is_equal_to(TransactionType::Resolve);
+        assert_that!(client.available).is_equal_to(dec!(3.0));
+        assert_that!(client.held).is_equal_to(dec!(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_stores() -> anyhow::Result<()> {
+        let records = [
+            vec!["deposit", "1", "1", "3.5"],
+            vec!["deposit", "2", "2", "2.0"],
+            vec!["widthdrawal", "1", "3", "1.0"],
+            vec!["dispute", "2", "2", ""],
+            vec!["chargeback", "2", "2", ""],
+        ];
+
+        let mut engine = Engine::new(Options::default());
+        let mut stored_engine = Engine::with_stores(
+            CountingStore::default(),
+            CountingStore::default(),
+            CountingStore::default(),
+            Options::default(),
+        );
+        for record in records {
+            engine.apply_record(&ByteRecord::from(record.clone()))?;
+            stored_engine.apply_record(&ByteRecord::from(record))?;
+        }
+        stored_engine.check_invariants()?;
+
+        assert_that!(stored_engine.past_transactions.len()).is_equal_to(3);
+        assert_that!(stored_engine.disputed_transactions.writes).is_equal_to(2);
+        assert!(stored_engine.disputed_transactions.is_empty());
+        let stored_clients = stored_engine.into_clients().entries;
+        assert_that!(stored_clients.len()).is_equal_to(2);
+        for (key, client) in stored_clients {
+            assert_that!(engine.clients()[&key].clone()).is_equal_to(client);
+        }
+
+        Ok(())
+    }
+
+    fn apply_timestamps(timestamps: &[&str], options: Options) -> anyhow::Result<Vec<bool>> {
+        let mut engine = Engine::new(options);
+        let mut succeeded = vec![];
+        for (tx, timestamp) in timestamps.iter().enumerate() {
+            let tx = (tx + 1).to_string();
+            let record = ByteRecord::from(vec!["deposit", "1", &tx, "1.0", "", timestamp]);
+            succeeded.push(engine.apply_record(&record)?.1.is_applied());
+        }
+        Ok(succeeded)
+    }
+
+    #[test]
+    fn test_require_monotonic_accepts_ordered() -> anyhow::Result<()> {
+        let options = Options {
+            require_monotonic: true,
+            ..Default::default()
+        };
+        let succeeded = apply_timestamps(&["100", "100", "", "250"], options)?;
+        assert_that!(succeeded).is_equal_to(vec![true, true, true, true]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_monotonic_rejects_out_of_order() -> anyhow::Result<()> {
+        let options = Options {
+            require_monotonic: true,
+            ..Default::default()
+        };
+        let succeeded = apply_timestamps(&["100", "250", "200", "300"], options)?;
+        assert_that!(succeeded).is_equal_to(vec![true, true, false, true]);
+
+        let succeeded = apply_timestamps(&["100", "250", "200", "300"], Options::default())?;
+        assert_that!(succeeded).is_equal_to(vec![true, true, true, true]);
+
+        Ok(())
+    }
+
+    fn engine_with(records: &[Vec<&str>]) -> anyhow::Result<Engine> {
+        let mut engine = Engine::new(Options::default());
+        for record in records {
+            engine.apply_record(&ByteRecord::from(record.clone()))?;
+        }
+        Ok(engine)
+    }
+
+    #[test]
+    fn test_finalize_reports_open_disputes() -> anyhow::Result<()> {
+        let records = [
+            vec!["deposit", "1", "1", "3.0"],
+            vec!["deposit", "1", "2", "2.0"],
+            vec!["deposit", "2", "3", "1.0"],
+            vec!["dispute", "1", "2", ""],
+            vec!["dispute", "2", "3", ""],
+            vec!["dispute", "1", "1", ""],
+            vec!["resolve", "1", "1", ""],
+        ];
+        let mut engine = engine_with(&records)?;
+        let open = engine.finalize()?;
+        assert_that!(open.iter().map(|t| t.tx).collect::<Vec<_>>())
+            .is_equal_to(vec![TxId(2), TxId(3)]);
+        assert_that!(engine.clients[&(ClientId(1), None)].held).is_equal_to(dec!(2));
+
+        let mut engine = Engine::new(Options {
+            open_disputes: OpenDisputes::Resolve,
+            ..Default::default()
+        });
+        for record in records {
+            engine.apply_record(&ByteRecord::from(record))?;
+        }
+        assert_that!(engine.finalize()?).has_length(2);
+        assert!(!engine.is_disputed(TxId(2)));
+        assert_that!(engine.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(engine.clients[&(ClientId(1), None)].available).is_equal_to(dec!(5));
+        // The 2 resolves are counted
+        assert_that!(engine.stats().applied).is_equal_to(9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deposit_delta_on_loaded_clients() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Options::default());
+        engine.load_clients(
+            b"client,available,held,total,locked
+1,1.5,0,1.5,false
+2,0,1,1,false
+3,0,0,0,true
+"
+            .as_slice(),
+        )?;
+
+        let (_, outcome) =
+            engine.apply_record(&ByteRecord::from(vec!["deposit", "1", "7", "2"]))?;
+        assert!(outcome.is_applied());
+        assert_that!(engine.clients()[&(ClientId(1), None)].available).is_equal_to(dec!(3.5));
+        assert_that!(engine.clients()[&(ClientId(1), None)].total).is_equal_to(dec!(3.5));
+        // The funds held by client 2 come from history which wasn't loaded
+        let (_, outcome) = engine.apply_record(&ByteRecord::from(vec!["resolve", "2", "1", ""]))?;
+        assert_that!(outcome.reason()).is_equal_to(Some(Reason::NotDisputed));
+        let (_, outcome) =
+            engine.apply_record(&ByteRecord::from(vec!["deposit", "3", "8", "1"]))?;
+        assert!(outcome.is_applied());
+        assert!(engine.clients()[&(ClientId(3), None)].locked);
+        engine.check_invariants()?;
+
+        assert!(engine
+            .load_clients(b"client,available,held,total,locked\n4,1,1,1,false\n".as_slice())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_clients_keeps_precision() -> anyhow::Result<()> {
+        let output = "client,available,held,total,locked
+1,0.1234567890123456789,0,0.1234567890123456789,false
+2,79228162514264337593543950335,0,79228162514264337593543950335,false
+";
+        let mut engine = Engine::new(Options::default());
+        engine.load_clients(output.as_bytes())?;
+        assert_that!(engine.clients()[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(0.1234567890123456789));
+        assert_that!(engine.clients()[&(ClientId(2), None)].total).is_equal_to(Decimal::MAX);
+
+        // The engine reloads its own output as it was written
+        let clients = engine.into_clients();
+        let written = write_clients(&clients, &Options::default(), vec![]).await?;
+        assert_that!(String::from_utf8(written)?).is_equal_to(output.to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transactions_history() -> anyhow::Result<()> {
+        let mut engine = engine_with(&[
+            vec!["deposit", "1", "1", "3.5"],
+            vec!["deposit", "1", "2", "1.0"],
+            vec!["widthdrawal", "1", "3", "9.0"],
+            vec!["dispute", "1", "1", ""],
+        ])?;
+
+        let transaction = engine.transaction(TxId(1)).unwrap();
+        assert_that!(transaction.amount).is_equal_to(Some(dec!(3.5)));
+        assert!(engine.is_disputed(TxId(1)));
+        assert!(engine.transaction(TxId(2)).is_some());
+        assert!(!engine.is_disputed(TxId(2)));
+        // Rejected and unknown transactions aren't recorded
+        assert!(engine.transaction(TxId(3)).is_none());
+        assert!(engine.transaction(TxId(4)).is_none());
+        assert!(!engine.is_disputed(TxId(4)));
+
+        engine.apply_record(&ByteRecord::from(vec!["resolve", "1", "1", ""]))?;
+        assert!(!engine.is_disputed(TxId(1)));
+        assert!(engine.transaction(TxId(1)).is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_from_wal() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("ex-test-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let records = [
+            vec!["deposit", "1", "1", "3.5"],
+            vec!["deposit", "2", "2", "2.0"],
+            vec!["widthdrawal", "2", "3", "0.5"],
+            vec!["dispute", "1", "1", ""],
+            vec!["widthdrawal", "2", "4", "9.0"],
+        ];
+
+        let mut engine = Engine::new(Options::default()).with_wal(&path)?;
+        for record in &records[..3] {
+            engine.apply_record(&ByteRecord::from(record.clone()))?;
+        }
+        // Crash
+        drop(engine);
+
+        // The replayed transactions already went through the hook before the crash
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut engine = Engine::new(Options::default())
+            .with_hook(move |_, _| {
+                counted.fetch_add(1, Ordering::Relaxed);
+            })
+            .with_wal(&path)?;
+        assert_that!(engine.stats().applied).is_equal_to(3);
+        assert_that!(calls.load(Ordering::Relaxed)).is_equal_to(0);
+        for record in &records[3..] {
+            engine.apply_record(&ByteRecord::from(record.clone()))?;
+        }
+        assert_that!(calls.load(Ordering::Relaxed)).is_equal_to(1);
+        drop(engine);
+
+        let recovered = Engine::new(Options::default()).with_wal(&path)?;
+        std::fs::remove_file(&path)?;
+        let expected = engine_with(&records)?;
+        assert_that!(recovered.clients().clone()).is_equal_to(expected.clients().clone());
+        assert!(recovered.is_disputed(TxId(1)));
+        assert_that!(recovered.stats().clone()).is_equal_to(expected.stats().clone());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_finalized_from_wal() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("ex-test-finalize-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = Options {
+            open_disputes: OpenDisputes::Resolve,
+            ..Default::default()
+        };
+
+        let mut engine = Engine::new(options.clone()).with_wal(&path)?;
+        for record in [
+            vec!["deposit", "1", "1", "3.5"],
+            vec!["deposit", "2", "2", "2.0"],
+            vec!["dispute", "1", "1", ""],
+            vec!["dispute", "2", "2", ""],
+        ] {
+            engine.apply_record(&ByteRecord::from(record))?;
+        }
+        assert_that!(engine.finalize()?).has_length(2);
+        assert_that!(engine.stats().applied).is_equal_to(6);
+        let summary = engine.summary();
+        assert_that!(summary.held).is_equal_to(dec!(0));
+        drop(engine);
+
+        // The resolves were logged, the recovered engine has nothing left to resolve
+        let mut recovered = Engine::new(options).with_wal(&path)?;
+        std::fs::remove_file(&path)?;
+        assert!(!recovered.is_disputed(TxId(1)));
+        assert!(!recovered.is_disputed(TxId(2)));
+        assert_that!(recovered.clients()[&(ClientId(1), None)].available).is_equal_to(dec!(3.5));
+        assert_that!(recovered.clients()[&(ClientId(2), None)].held).is_equal_to(dec!(0));
+        assert_that!(recovered.stats().clone()).is_equal_to(summary.stats);
+        assert_that!(recovered.finalize()?).is_empty();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_maps() -> anyhow::Result<()> {
+        let engine = engine_with(&[
+            vec!["deposit", "2", "2", "2.0"],
+            vec!["deposit", "1", "1", "3.5"],
+            vec!["dispute", "1", "1", ""],
+        ])?;
+        let mut dump = vec![];
+        engine.dump_maps(&mut dump)?;
+
+        let dump: serde_json::Value = serde_json::from_slice(&dump)?;
+        assert_that!(dump["clients"][0]["id"].clone()).is_equal_to(serde_json::json!(1));
+        assert_that!(dump["clients"][0]["held"].clone()).is_equal_to(serde_json::json!("3.5"));
+        assert_that!(dump["clients"][1]["id"].clone()).is_equal_to(serde_json::json!(2));
+        let txs = |map: &str| {
+            dump[map]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|transaction| transaction["tx"].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_that!(txs("past_transactions"))
+            .is_equal_to(vec![serde_json::json!(1), serde_json::json!(2)]);
+        assert_that!(txs("disputed_transactions")).is_equal_to(vec![serde_json::json!(1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_maps() -> anyhow::Result<()> {
+        let engine = engine_with(&[
+            vec!["deposit", "2", "2", "2.0"],
+            vec!["deposit", "1", "1", "3.5"],
+            vec!["dispute", "1", "1", ""],
+        ])?;
+        let mut dump = vec![];
+        engine.dump_maps(&mut dump)?;
+
+        let mut restored = Engine::new(Options::default());
+        restored.load_maps(dump.as_slice())?;
+        assert_that!(restored.clients).is_equal_to(engine.clients.clone());
+        let mut restored_dump = vec![];
+        restored.dump_maps(&mut restored_dump)?;
+        assert_that!(restored_dump).is_equal_to(dump);
+        // The dispute can go on
+        let (_, outcome) =
+            restored.apply_record(&ByteRecord::from(vec!["resolve", "1", "1", ""]))?;
+        assert_that!(outcome).is_equal_to(Outcome::Applied);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_disjoint_clients() -> anyhow::Result<()> {
+        let mut engine = engine_with(&[
+            vec!["deposit", "1", "1", "3.5"],
+            vec!["dispute", "1", "1", ""],
+        ])?;
+        let other = engine_with(&[
+            vec!["deposit", "2", "2", "2.0"],
+            vec!["widthdrawal", "2", "3", "0.5"],
+            vec!["deposit", "2", "4", "1.0"],
+        ])?;
+
+        engine.merge(other)?;
+        engine.check_invariants()?;
+        assert_that!(engine.stats().applied).is_equal_to(5);
+        assert_that!(engine.clients().len()).is_equal_to(2);
+        assert_that!(engine.clients()[&(ClientId(1), None)].held).is_equal_to(dec!(3.5));
+        assert_that!(engine.clients()[&(ClientId(2), None)].available).is_equal_to(dec!(2.5));
+
+        // The merged history still serves disputes
+        let (_, outcome) = engine.apply_record(&ByteRecord::from(vec!["dispute", "2", "2", ""]))?;
+        assert!(outcome.is_applied());
+        engine.apply_record(&ByteRecord::from(vec!["resolve", "1", "1", ""]))?;
+        assert_that!(engine.clients()[&(ClientId(1), None)].available).is_equal_to(dec!(3.5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_overlapping_client() -> anyhow::Result<()> {
+        let mut engine = engine_with(&[vec!["deposit", "1", "1", "3.5"]])?;
+        let other = engine_with(&[
+            vec!["deposit", "2", "2", "2.0"],
+            vec!["deposit", "1", "3", "1.0"],
+        ])?;
+
+        assert_that!(engine.merge(other).unwrap_err().to_string())
+            .is_equal_to("Client 1 was processed by both engines".to_string());
+        // Nothing was merged
+        assert_that!(engine.clients().len()).is_equal_to(1);
+        assert_that!(engine.clients()[&(ClientId(1), None)].total).is_equal_to(dec!(3.5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_overlapping_tx() -> anyhow::Result<()> {
+        let mut engine = engine_with(&[vec!["deposit", "1", "1", "3.5"]])?;
+        let other = engine_with(&[vec!["deposit", "2", "1", "2.0"]])?;
+
+        assert_that!(engine.merge(other).unwrap_err().to_string())
+            .is_equal_to("Tx 1 was processed by both engines".to_string());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+//! Live feeds: inputs such as a FIFO which only end when their writer closes them. The clients
+//! are snapshotted periodically while the rows keep coming, including when the feed is idle.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+use tokio_stream::StreamExt;
+
+use super::parser::{create_deserializer, process_row};
+use super::processor::Processor;
+use super::{write_clients, ClientHash, Engine};
+use crate::entities::transaction::Transaction;
+use crate::options::Options;
+
+/// Same as [`super::parse_into_engine`], sending the clients so far to `snapshots` every
+/// `interval` until `reader` ends. A tick is skipped while `snapshots` is full, only the latest
+/// snapshot mattering. `options.read_ahead` and `options.batch_size` aren't used.
+pub async fn parse_live_into_engine<R, T>(
+    reader: R,
+    options: &Options,
+    trace: &mut T,
+    interval: Duration,
+    snapshots: mpsc::Sender<ClientHash>,
+) -> anyhow::Result<Engine>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let mut rdr = create_deserializer(reader, options).await?;
+    let mut processor = Processor::new(options, trace)?;
+    let mut transactions = rdr.deserialize::<Transaction>();
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes right away, there's nothing to snapshot yet
+    ticks.tick().await;
+
+    loop {
+        tokio::select! {
+            transaction = transactions.next() => match transaction {
+                Some(_) if processor.interrupted() => break,
+                Some(transaction) => process_row(&mut processor, transaction)?,
+                None => break,
+            },
+            _ = ticks.tick() => {
+                // Nobody listening anymore isn't an error for the run itself, and the clients
+                // are only cloned once the writer caught up
+                if let Ok(permit) = snapshots.try_reserve() {
+                    permit.send(processor.clients().clone());
+                }
+            }
+        }
+    }
+
+    processor.finish()
+}
+
+/// Writes every snapshot received on `snapshots` to `path`, through a temporary file renamed
+/// over it so readers never see a partial snapshot
+pub async fn write_live_snapshots(
+    mut snapshots: mpsc::Receiver<ClientHash>,
+    options: Options,
+    path: PathBuf,
+) -> anyhow::Result<()> {
+    let temporary = temporary_path(&path);
+    while let Some(clients) = snapshots.recv().await {
+        write_clients(&clients, &options, File::create(&temporary).await?).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+    }
+    Ok(())
+}
+
+/// `path` with a `.tmp` suffix
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::id::ClientId;
+    use assertor::*;
+    use rust_decimal_macros::dec;
+    use std::io;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::timeout;
+
+    /// The next snapshot, failing instead of hanging when none comes
+    async fn next_snapshot(receiver: &mut mpsc::Receiver<ClientHash>) -> ClientHash {
+        timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .expect("a snapshot in time")
+            .expect("a snapshot")
+    }
+
+    #[tokio::test]
+    async fn test_live_snapshots() -> anyhow::Result<()> {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let (sender, mut receiver) = mpsc::channel(1);
+        let options = Options::default();
+        let live = tokio::spawn(async move {
+            parse_live_into_engine(
+                reader,
+                &options,
+                &mut io::sink(),
+                Duration::from_millis(20),
+                sender,
+            )
+            .await
+        });
+
+        writer
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n")
+            .await?;
+        // Snapshots keep coming while the feed is idle
+        let mut snapshot = next_snapshot(&mut receiver).await;
+        while snapshot.is_empty() {
+            snapshot = next_snapshot(&mut receiver).await;
+        }
+        assert_that!(snapshot[&(ClientId(1), None)].total).is_equal_to(dec!(1.0));
+
+        writer.write_all(b"deposit,1,2,2.0\n").await?;
+        while snapshot[&(ClientId(1), None)].total != dec!(3.0) {
+            snapshot = next_snapshot(&mut receiver).await;
+        }
+
+        // The feed ends when its writer closes it
+        drop(writer);
+        let engine = timeout(Duration::from_secs(10), live).await???;
+        assert_that!(engine.clients()[&(ClientId(1), None)].total).is_equal_to(dec!(3.0));
+        while receiver.recv().await.is_some() {}
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_live_snapshots_dont_pile_up() -> anyhow::Result<()> {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let (sender, mut receiver) = mpsc::channel(1);
+        let options = Options::default();
+        let live = tokio::spawn(async move {
+            parse_live_into_engine(
+                reader,
+                &options,
+                &mut io::sink(),
+                Duration::from_millis(5),
+                sender,
+            )
+            .await
+        });
+
+        writer
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n")
+            .await?;
+        // Many ticks go by without the snapshots being read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(writer);
+        timeout(Duration::from_secs(10), live).await???;
+
+        let mut pending = 0;
+        while receiver.recv().await.is_some() {
+            pending += 1;
+        }
+        assert_that!(pending).is_equal_to(1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_live_snapshots() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("ex-live-{}.csv", std::process::id()));
+        let (sender, receiver) = mpsc::channel(1);
+        let writer = tokio::spawn(write_live_snapshots(
+            receiver,
+            Options::default(),
+            path.clone(),
+        ));
+
+        let mut engine = Engine::new(Options::default());
+        for (tx, amount) in [("1", "1.0"), ("2", "2.0")] {
+            engine.apply_record(&csv::ByteRecord::from(vec!["deposit", "1", tx, amount]))?;
+            sender.send(engine.clients().clone()).await?;
+        }
+        drop(sender);
+        writer.await??;
+
+        let written = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_that!(written)
+            .is_equal_to("client,available,held,total,locked\n1,3,0,3,false\n".to_string());
+        assert!(!temporary_path(&path).exists());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,34 @@
+/// Prints a human readable diagnostic on STDERR, unless `options` asked for JSON logs: rejected
+/// and invalid rows are then logged by the processor, other diagnostics as [`Notice`]s
+macro_rules! diagnostic {
+    ($options:expr, $($arg:tt)*) => {
+        if $options.log_format == crate::options::LogFormat::Text {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+mod batched;
+mod engine;
+mod live;
+mod notice;
 #[allow(clippy::module_inception)]
 mod parser;
+mod processor;
+mod read_ahead;
+mod sharded;
+mod stats;
+mod store;
+
+use notice::{Notice, NoticeReason};
 
-pub use parser::parse_data;
+pub use engine::Engine;
+pub use live::{parse_live_into_engine, write_live_snapshots};
+pub use parser::{
+    checksum, parse_chained_into_engine, parse_data, parse_file_sync, parse_into_engine,
+    parse_merged_into_engine, parse_records_into_engine, parse_snapshots, parse_transactions,
+    replay_from_snapshot, write_clients, ClientHash, ClientKey, TransactionHash,
+};
+pub use sharded::parse_sharded_into_engine;
+pub use stats::{RunStats, Summary};
+pub use store::Store;
@@ -0,0 +1,56 @@
+use serde::Serialize;
+use std::io::Write;
+
+use crate::entities::id::{ClientId, TxId};
+use crate::options::{LogFormat, Options};
+
+/// What a [`Notice`] is about, its `reason` with `--log-format json`
+#[derive(Debug, Serialize, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NoticeReason {
+    /// A balance reached the 28 significant digits of `Decimal` and was rounded
+    PrecisionLost,
+    /// Rows before `--begin` aren't applied
+    SkippedRows,
+    /// Disputes left open at the end of the input
+    OpenDisputes,
+    /// A client left out of the output by `--omit-inactive`
+    InactiveClient,
+}
+
+/// A diagnostic which isn't about a rejected or invalid row. Written as is with
+/// `--log-format text`, and as a JSON line with the fields of the rejections otherwise, `row`,
+/// `tx` and `client` being empty when it isn't about a single row.
+#[derive(Debug, Serialize)]
+pub(crate) struct Notice {
+    /// 1-based index of the row in the input, headers excluded
+    pub row: Option<usize>,
+    pub tx: Option<TxId>,
+    pub client: Option<ClientId>,
+    pub reason: NoticeReason,
+    pub message: String,
+}
+
+impl Notice {
+    pub fn new(reason: NoticeReason, message: String) -> Self {
+        Notice {
+            row: None,
+            tx: None,
+            client: None,
+            reason,
+            message,
+        }
+    }
+
+    /// Writes the notice to `output` in the `--log-format` of `options`
+    pub fn write<W: Write>(&self, options: &Options, output: &mut W) -> anyhow::Result<()> {
+        match options.log_format {
+            LogFormat::Text => writeln!(output, "{}", self.message)?,
+            LogFormat::Json => {
+                serde_json::to_writer(&mut *output, self)?;
+                writeln!(output)?;
+            }
+        }
+        Ok(())
+    }
+}
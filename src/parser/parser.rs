@@ -1,730 +1,3672 @@
+use anyhow::{bail, Context};
 use csv::ByteRecord;
-use csv_async::Trim;
+use csv_async::{AsyncDeserializer, Trim};
+use rust_decimal::Decimal;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 use tokio_stream::StreamExt;
 
+use super::processor::Processor;
+use super::store::Store;
+use super::{
+    batched, parse_live_into_engine, parse_sharded_into_engine, read_ahead, write_live_snapshots,
+    Engine, Notice, NoticeReason,
+};
+use crate::diff;
 use crate::entities::client::Client;
-use crate::entities::transaction::{Transaction, TransactionType};
+use crate::entities::error::{OutputError, TransactionError};
+use crate::entities::id::{ClientId, TxId};
+use crate::entities::transaction::{Outcome, Reason, Transaction, TransactionType};
+use crate::input;
+use crate::options::{AccountPolicy, LogFormat, OpenDisputes, Options, OutputFormat, SortBy};
+#[cfg(feature = "gzip")]
+use crate::output::gzip;
+#[cfg(feature = "parquet")]
+use crate::output::parquet;
+#[cfg(feature = "sqlite")]
+use crate::output::sqlite;
+use crate::output::{coalesced, OutputSink};
 
-type TransactionHash = HashMap<u32, Transaction>;
-type ClientHash = HashMap<u16, Client>;
+pub type TransactionHash = HashMap<TxId, Transaction>;
+/// Clients are tracked per currency, `None` being the default currency
+pub type ClientKey = (ClientId, Option<String>);
+pub type ClientHash = HashMap<ClientKey, Client>;
 
-/// Will parse the given `file_name` as a stream input then write the result in `output`
-pub async fn parse_data(file_name: &str) -> anyhow::Result<()> {
+/// Will parse the given `options.input` as a stream input then write the result in `output`.
+/// Inputs given with `options.merge` are merged with it in timestamp order.
+pub async fn parse_data(options: &Options) -> anyhow::Result<()> {
+    let input = options
+        .input
+        .as_deref()
+        .context("Call with filename input")?;
+    options.output.check_writable()?;
+    let parquet = input::is_parquet(input);
+    if parquet
+        && (options.snapshot_per_input
+            || options.replay_from_snapshot.is_some()
+            || options.snapshot_interval_ms.is_some()
+            || options.shards > 1
+            || !options.merge.is_empty())
+    {
+        bail!("A Parquet input can only be applied on its own");
+    }
+    if options.snapshot_per_input {
+        return write_snapshots(input, options).await;
+    }
+    let engine = if parquet {
+        let records = input::read_parquet(input)?;
+        parse_records_into_engine(records, options, &mut io::stderr())?
+    } else if let Some(row) = options.replay_from_snapshot {
+        let mut readers = open_inputs(input, options).await?;
+        if readers.len() != 1 {
+            bail!("--replay-from-snapshot needs a single CSV input");
+        }
+        let mut data = vec![];
+        readers[0].read_to_end(&mut data).await?;
+        replay_from_snapshot(&data, row, options, &mut io::stderr()).await?
+    } else if let Some(interval) = options.snapshot_interval_ms {
+        parse_live(input, Duration::from_millis(interval), options).await?
+    } else if options.shards > 1 {
+        let readers = open_inputs(input, options).await?;
+        parse_sharded_into_engine(readers, options.shards, options).await?
+    } else if options.merge.is_empty() {
+        let readers = open_inputs(input, options).await?;
+        parse_chained_into_engine(readers, options, &mut io::stderr()).await?
+    } else {
+        let mut readers = open_inputs(input, options).await?;
+        for path in &options.merge {
+            readers.extend(open_inputs(path, options).await?);
+        }
+        parse_merged_into_engine(readers, options, &mut io::stderr()).await?
+    };
+
+    if options.compact {
+        let format = options.report_format.unwrap_or_default();
+        let summary = engine.summary().report(format)?;
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(summary.as_bytes()).await?;
+        stdout.flush().await?;
+        return Ok(());
+    }
+    if let Some(format) = options.report_format {
+        eprint!("{}", engine.summary().report(format)?);
+    }
+    if options.coalesce_deposits {
+        let transactions = coalesced::coalesce_deposits(engine.past_transactions());
+        return match &options.output {
+            OutputSink::Stdout => {
+                let output = coalesced::write_transactions(&transactions, vec![]).await?;
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(&output).await?;
+                stdout.flush().await?;
+                Ok(())
+            }
+            OutputSink::File(path) => {
+                coalesced::write_transactions(&transactions, File::create(path).await?).await?;
+                Ok(())
+            }
+            #[cfg(feature = "sqlite")]
+            OutputSink::Sqlite(_) => bail!("--coalesce-deposits only writes CSV"),
+        };
+    }
+    let mut clients = engine.into_clients();
+    if let (true, Some(path)) = (options.changed_only, &options.initial_state) {
+        diff::retain_changed(&mut clients, std::fs::File::open(path)?)?;
+    }
+    report_inactive(&clients, options, &mut io::stderr())?;
+    if options.emit_checksum {
+        eprintln!("sha256:{}", checksum(&clients, options).await?);
+    }
+
+    // 2. Output
+    if options.format == OutputFormat::Parquet {
+        let path = match &options.output {
+            OutputSink::File(path) => path,
+            _ => bail!("--format parquet requires an --output file"),
+        };
+        #[cfg(feature = "parquet")]
+        return parquet::write_clients(&clients, options, path);
+        #[cfg(not(feature = "parquet"))]
+        bail!(
+            "Can't write to {}, Parquet support requires the parquet feature",
+            path.display()
+        );
+    }
+
+    if options.gzip_output || options.output.is_gzip() {
+        #[cfg(feature = "gzip")]
+        return gzip::write_clients(&clients, options).await;
+        #[cfg(not(feature = "gzip"))]
+        bail!("Can't write the output gzipped, gzip support requires the gzip feature");
+    }
+    match &options.output {
+        OutputSink::Stdout if options.streaming_output => {
+            write_clients(&clients, options, tokio::io::stdout()).await?;
+        }
+        OutputSink::Stdout => {
+            // Written as bytes, string fields such as currencies may hold any UTF-8
+            let mut output = write_clients(&clients, options, vec![]).await?;
+            output.push(b'\n');
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(&output).await?;
+            stdout.flush().await?;
+        }
+        OutputSink::File(path) => {
+            write_clients(&clients, options, File::create(path).await?).await?;
+        }
+        #[cfg(feature = "sqlite")]
+        OutputSink::Sqlite(path) => sqlite::write_clients(&clients, options, path)?,
+    }
+
+    Ok(())
+}
+
+/// Opens `path` with [`input::open_all`], preceding each input with the `options.schema`
+/// headers when it has none
+async fn open_inputs(path: &str, options: &Options) -> anyhow::Result<Vec<input::Input>> {
+    let inputs = input::open_all(path).await?;
+    if !options.no_headers {
+        return Ok(inputs);
+    }
+    let schema = options.schema()?;
+    Ok(inputs
+        .into_iter()
+        .map(|input| input::with_headers(input, &schema))
+        .collect())
+}
+
+/// Same as [`parse_data`] for callers outside of an async context, running it on a current
+/// thread runtime of its own. Panics when called from within a runtime.
+pub fn parse_file_sync(options: &Options) -> anyhow::Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(parse_data(options))
+}
+
+/// Applies `input` then the `options.merge` inputs one after the other, writing the clients
+/// after each of them next to the `--output` file: `out.csv` gets `out.1.csv`, `out.2.csv`, ...
+async fn write_snapshots(input: &str, options: &Options) -> anyhow::Result<()> {
+    let path = match &options.output {
+        OutputSink::File(path) => path,
+        _ => bail!("--snapshot-per-input requires an --output file"),
+    };
+    let mut readers = open_inputs(input, options).await?;
+    for path in &options.merge {
+        readers.extend(open_inputs(path, options).await?);
+    }
+
+    let snapshots = parse_snapshots(readers, options, &mut io::stderr()).await?;
+    for (index, clients) in snapshots.iter().enumerate() {
+        let snapshot = snapshot_path(path, index + 1);
+        write_clients(clients, options, File::create(&snapshot).await?).await?;
+    }
+
+    Ok(())
+}
+
+/// Applies `input` as a live feed, writing the clients to the `--output` file every `interval`
+async fn parse_live(input: &str, interval: Duration, options: &Options) -> anyhow::Result<Engine> {
+    let path = match &options.output {
+        OutputSink::File(path) => path.clone(),
+        _ => bail!("--snapshot-interval-ms requires an --output file"),
+    };
+    let mut readers = open_inputs(input, options).await?;
+    if readers.len() != 1 {
+        bail!("--snapshot-interval-ms needs a single CSV input");
+    }
+    let (sender, receiver) = mpsc::channel(1);
+    let writer = tokio::spawn(write_live_snapshots(receiver, options.clone(), path));
+    let engine = parse_live_into_engine(
+        readers.remove(0),
+        options,
+        &mut io::stderr(),
+        interval,
+        sender,
+    )
+    .await;
+    writer.await??;
+    engine
+}
+
+/// `path` with `index` inserted before its extension
+fn snapshot_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push(format!(".{}", index));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Writes a notice to `trace` for each client left out of the output by `--omit-inactive`,
+/// ordered by id and currency
+fn report_inactive<W: Write>(
+    clients: &ClientHash,
+    options: &Options,
+    trace: &mut W,
+) -> anyhow::Result<()> {
+    if !options.omit_inactive {
+        return Ok(());
+    }
+    let mut inactive = clients
+        .iter()
+        .filter(|(_, client)| !client.active)
+        .collect::<Vec<_>>();
+    inactive.sort_by_key(|(key, _)| *key);
+    for (_, client) in inactive {
+        let notice = Notice {
+            client: Some(client.id),
+            ..Notice::new(
+                NoticeReason::InactiveClient,
+                format!(
+                    "Leaving out client {}, none of its transactions went through",
+                    client.id
+                ),
+            )
+        };
+        notice.write(options, trace)?;
+    }
+    Ok(())
+}
+
+/// Writes `clients` as CSV into `output`, keeping only the ones selected by `options`.
+///
+/// Clients are sorted by `options.sort_by`, which requires collecting them all first. With
+/// `options.streaming_output` they are written in no particular order straight from the map,
+/// flushing every `options.flush_every` rows or `options.flush_interval_ms`, whichever comes
+/// first, which keeps memory flat for very wide client sets.
+pub async fn write_clients<W>(
+    clients: &ClientHash,
+    options: &Options,
+    mut output: W,
+) -> anyhow::Result<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    // The currency column is only emitted when the input used currencies
+    let with_currency = clients.values().any(|client| client.currency.is_some());
+    let mut headers = Client::headers();
+    if with_currency {
+        headers.push("currency");
+    }
+    if options.gross_totals {
+        headers.extend(Client::gross_headers());
+    }
+    if options.cents_columns {
+        headers.extend(Client::cents_headers());
+    }
+
+    if options.header_comment {
+        output.write_all(header_comment().as_bytes()).await?;
+    }
+    let mut wtr = csv_async::AsyncWriter::from_writer(output);
+    wtr.write_record(headers).await?;
+
+    let clients = clients.values().filter(|client| options.outputs(client));
+    if options.streaming_output {
+        let interval = options.flush_interval_ms.map(Duration::from_millis);
+        let mut last_flush = Instant::now();
+        for (written, client) in clients.enumerate() {
+            wtr.write_record(&client_record(client, with_currency, options)?)
+                .await?;
+            let batch_full = options.flush_every > 0 && (written + 1) % options.flush_every == 0;
+            if batch_full || interval.is_some_and(|interval| last_flush.elapsed() >= interval) {
+                wtr.flush().await?;
+                last_flush = Instant::now();
+            }
+        }
+    } else {
+        let mut clients = clients.collect::<Vec<_>>();
+        clients.sort_by(|a, b| {
+            let order = match options.sort_by {
+                SortBy::Client => Ordering::Equal,
+                SortBy::Total => a.total.cmp(&b.total),
+                SortBy::Available => a.available.cmp(&b.available),
+            };
+            // Ties are ordered by id so the output is reproducible
+            order.then_with(|| (a.id, &a.currency).cmp(&(b.id, &b.currency)))
+        });
+        for client in clients {
+            wtr.write_record(&client_record(client, with_currency, options)?)
+                .await?;
+        }
+    }
+
+    Ok(wtr.into_inner().await?)
+}
+
+/// Provenance line written before the CSV headers with `options.header_comment`
+fn header_comment() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "# {} {}, generated at {} (unix seconds)\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        now
+    )
+}
+
+/// SHA-256 of the canonical CSV output of `clients`, hex encoded: sorted by client id whatever
+/// `options.sort_by`, amounts without trailing zeros. Two runs with equal outputs share it.
+pub async fn checksum(clients: &ClientHash, options: &Options) -> anyhow::Result<String> {
+    let options = Options {
+        sort_by: SortBy::Client,
+        streaming_output: false,
+        header_comment: false,
+        ..options.clone()
+    };
+    let output = write_clients(clients, &options, vec![]).await?;
+    Ok(Sha256::digest(output)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Converts `client` into a CSV record, with trailing currency, gross and cents columns if asked
+/// to.
+/// Amounts are rounded to the scale of the client's currency when it's known.
+fn client_record(
+    client: &Client,
+    with_currency: bool,
+    options: &Options,
+) -> Result<ByteRecord, OutputError> {
+    let client = &options.output_client(client);
+    let mut record = ByteRecord::from(client);
+    if with_currency {
+        record.push_field(client.currency.as_deref().unwrap_or_default().as_bytes());
+    }
+    if options.gross_totals {
+        for field in client.gross_fields() {
+            record.push_field(field.as_bytes());
+        }
+    }
+    if options.cents_columns {
+        for field in client.cents_fields()? {
+            record.push_field(field.as_bytes());
+        }
+    }
+    Ok(record)
+}
+
+/// Parses all transactions from `reader` and returns the resulting clients.
+/// Rows which can't be deserialized (invalid UTF-8, bad values, ...) are skipped.
+/// Once `options.interrupt` is set, the remaining rows are left unread.
+/// With `options.trace_state`, a JSON snapshot of the affected client is written to `trace`
+/// after each applied transaction.
+pub async fn parse_transactions<R, T>(
+    reader: R,
+    options: &Options,
+    trace: &mut T,
+) -> anyhow::Result<ClientHash>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    Ok(parse_into_engine(reader, options, trace)
+        .await?
+        .into_clients())
+}
+
+/// Same as [`parse_transactions`] but returns the whole engine, transactions history included
+pub async fn parse_into_engine<R, T>(
+    reader: R,
+    options: &Options,
+    trace: &mut T,
+) -> anyhow::Result<Engine>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let mut processor = Processor::new(options, trace)?;
+    parse_into_processor(reader, &mut processor, options).await?;
+    processor.finish()
+}
+
+/// Same as [`parse_into_engine`] over several `readers`, applied one after the other as if they
+/// were concatenated. Rows are numbered across all the readers.
+pub async fn parse_chained_into_engine<R, T>(
+    readers: Vec<R>,
+    options: &Options,
+    trace: &mut T,
+) -> anyhow::Result<Engine>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let mut processor = Processor::new(options, trace)?;
+    for reader in readers {
+        if processor.interrupted() {
+            break;
+        }
+        parse_into_processor(reader, &mut processor, options).await?;
+    }
+    processor.finish()
+}
+
+/// Same as [`parse_into_engine`] over records already split into the [`Transaction::headers`]
+/// columns, e.g. the rows of a Parquet input read batch by batch. They're deserialized like CSV
+/// rows, the run failing on the first record which couldn't be read.
+pub fn parse_records_into_engine<I, T>(
+    records: I,
+    options: &Options,
+    trace: &mut T,
+) -> anyhow::Result<Engine>
+where
+    I: IntoIterator<Item = anyhow::Result<csv_async::ByteRecord>>,
+    T: Write,
+{
+    let headers = csv_async::ByteRecord::from(Transaction::headers());
+    let column = type_column(&headers, options);
+    let amount = amount_column(&headers, options);
+    let mut processor = Processor::new(options, trace)?;
+    for record in records {
+        if processor.interrupted() {
+            break;
+        }
+        let mut record = record?;
+        if let Some(column) = column {
+            normalize_type(&mut record, column);
+        }
+        if let Some(amount) = amount {
+            normalize_amount(&mut record, amount, options);
+        }
+        process_row(
+            &mut processor,
+            record.deserialize::<Transaction>(Some(&headers)),
+        )?;
+    }
+    processor.finish()
+}
+
+/// Self-check of the engine snapshots: runs `input` from scratch, then again up to `row` only,
+/// restores a fresh engine from a [`Engine::dump_maps`] snapshot of it and applies the remaining
+/// rows. Fails if the two runs don't end with the same maps, else returns the engine of the
+/// full run.
+pub async fn replay_from_snapshot<T: Write>(
+    input: &[u8],
+    row: usize,
+    options: &Options,
+    trace: &mut T,
+) -> anyhow::Result<Engine> {
+    let full = parse_into_engine(input, options, trace).await?;
+
+    // The replays are silent and don't write any file
+    let replay_options = Options {
+        log_format: LogFormat::Json,
+        audit_log: None,
+        dump_maps: None,
+        ..options.clone()
+    };
+    // The input goes on after the snapshot, its disputes stay open
+    let head_options = Options {
+        end: Some(row),
+        open_disputes: OpenDisputes::Hold,
+        ..replay_options.clone()
+    };
+    let head = parse_into_engine(input, &head_options, &mut io::sink()).await?;
+    let mut snapshot = vec![];
+    head.dump_maps(&mut snapshot)?;
+
+    let mut restored = Engine::new(replay_options.clone());
+    restored.load_maps(snapshot.as_slice())?;
+    let tail_options = Options {
+        begin: Some(row + 1),
+        ..replay_options
+    };
+    let mut sink = io::sink();
+    let mut processor = Processor::with_engine(&tail_options, &mut sink, restored)?;
+    parse_into_processor(input, &mut processor, &tail_options).await?;
+    let replayed = processor.finish()?;
+
+    compare_replay(row, &full, &replayed)?;
+    Ok(full)
+}
+
+/// Fails if the maps of the `replayed` engine aren't those of the `expected` one. Clients
+/// restored from a snapshot are all active, that flag isn't compared.
+fn compare_replay(row: usize, expected: &Engine, replayed: &Engine) -> anyhow::Result<()> {
+    for (key, client) in expected.clients().entries() {
+        let replayed_client = replayed
+            .clients()
+            .get(&key)
+            .cloned()
+            .map(|replayed_client| Client {
+                active: client.active,
+                ..replayed_client
+            });
+        if replayed_client.as_ref() != Some(&client) {
+            bail!(
+                "Replay from the snapshot at row {} diverges for client {}: {:?} instead of {:?}",
+                row,
+                client.id,
+                replayed_client,
+                client
+            );
+        }
+    }
+    if replayed.clients().len() != expected.clients().len() {
+        bail!(
+            "Replay from the snapshot at row {} ends with {} clients instead of {}",
+            row,
+            replayed.clients().len(),
+            expected.clients().len()
+        );
+    }
+
+    let (mut expected_maps, mut replayed_maps) = (vec![], vec![]);
+    expected.dump_maps(&mut expected_maps)?;
+    replayed.dump_maps(&mut replayed_maps)?;
+    if replayed_maps != expected_maps {
+        bail!(
+            "Replay from the snapshot at row {} diverges in the transactions history",
+            row
+        );
+    }
+    Ok(())
+}
+
+/// Applies the rows of `readers` one reader after the other, as if they were concatenated, and
+/// returns the clients after each of them: the cumulative state at the end of every input.
+/// Rows are numbered across all the readers.
+pub async fn parse_snapshots<R, T>(
+    readers: Vec<R>,
+    options: &Options,
+    trace: &mut T,
+) -> anyhow::Result<Vec<ClientHash>>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let mut processor = Processor::new(options, trace)?;
+    let mut snapshots = Vec::with_capacity(readers.len());
+    for reader in readers {
+        if processor.interrupted() {
+            break;
+        }
+        parse_into_processor(reader, &mut processor, options).await?;
+        snapshots.push(processor.clients().clone());
+    }
+    processor.finish()?;
+
+    Ok(snapshots)
+}
+
+/// Feeds `processor` with the rows of `reader`
+async fn parse_into_processor<R, T>(
+    reader: R,
+    processor: &mut Processor<'_, T>,
+    options: &Options,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let mut rdr = create_deserializer(reader, options).await?;
+
+    if options.read_ahead > 0 {
+        read_ahead::parse_records(&mut rdr, processor, options).await?;
+    } else if options.batch_size > 0 || options.normalize_types || options.rewrites_amounts() {
+        batched::parse_records(&mut rdr, processor, options).await?;
+    } else {
+        let mut transactions = rdr.deserialize::<Transaction>();
+        while let Some(transaction) = transactions.next().await {
+            if processor.interrupted() {
+                break;
+            }
+            process_row(processor, transaction)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Same as [`parse_into_engine`] over several `readers`, each sorted by timestamp, which are
+/// merged into a single timestamp ordered stream. Rows without a timestamp or which can't be
+/// deserialized are processed as soon as they come up, equal timestamps keep the order of
+/// `readers`. `options.read_ahead`, `options.batch_size`, `options.normalize_types` and the
+/// amount rewrites aren't used.
+pub async fn parse_merged_into_engine<R, T>(
+    readers: Vec<R>,
+    options: &Options,
+    trace: &mut T,
+) -> anyhow::Result<Engine>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let mut rdrs = Vec::with_capacity(readers.len());
+    for reader in readers {
+        rdrs.push(create_deserializer(reader, options).await?);
+    }
+    let mut processor = Processor::new(options, trace)?;
+
+    // k-way merge, keeping the next row of each reader
+    let mut streams = rdrs
+        .iter_mut()
+        .map(|rdr| rdr.deserialize::<Transaction>())
+        .collect::<Vec<_>>();
+    let mut heads = Vec::with_capacity(streams.len());
+    for stream in &mut streams {
+        heads.push(stream.next().await);
+    }
+
+    loop {
+        let next = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(shard, head)| Some((shard, head.as_ref()?)))
+            .min_by_key(|(_, row)| row.as_ref().ok().and_then(|t| t.timestamp))
+            .map(|(shard, _)| shard);
+        let shard = match next {
+            Some(shard) if !processor.interrupted() => shard,
+            _ => break,
+        };
+
+        let next_head = streams[shard].next().await;
+        if let Some(row) = std::mem::replace(&mut heads[shard], next_head) {
+            process_row(&mut processor, row)?;
+        }
+    }
+
+    processor.finish()
+}
+
+/// Deserializer over `reader`, checking its columns first with `options.strict_columns`
+pub(super) async fn create_deserializer<R>(
+    reader: R,
+    options: &Options,
+) -> anyhow::Result<AsyncDeserializer<R>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    // Flexible as trailing commas are often left out, e.g. `dispute,1,1` without an amount
     let mut rdr = csv_async::AsyncReaderBuilder::new()
         .has_headers(true)
         .trim(Trim::All)
-        .create_deserializer(File::open(file_name).await?);
-
-    let mut transactions = rdr.deserialize::<Transaction>();
-
-    // TODO: those would usually be stored in a DB but for simplicity of this exercise we keep them in memory
-    let mut clients = HashMap::new();
-    let mut past_transactions = HashMap::new();
-    let mut disputed_transactions = HashMap::new();
-
-    // 1. Parsing input
-    while let Some(transaction) = transactions.next().await {
-        let mut transaction = transaction?;
-        parse_single_transaction(
-            &mut transaction,
-            &mut clients,
-            &mut past_transactions,
-            &mut disputed_transactions,
-        )?;
+        .flexible(true)
+        .create_deserializer(reader);
+
+    if options.strict_columns {
+        let known = Transaction::headers();
+        if let Some(column) = rdr
+            .headers()
+            .await?
+            .iter()
+            .find(|column| !known.contains(column))
+        {
+            bail!("Unexpected column {} in input", column);
+        }
     }
 
-    // 2. Output
-    let mut wtr = csv_async::AsyncWriter::from_writer(vec![]);
-    wtr.write_record(Client::headers()).await?;
-    for (_, client) in clients {
-        wtr.write_record(&ByteRecord::from(client)).await?;
+    Ok(rdr)
+}
+
+/// Index of the `type` column in `headers` when its values are to be normalized with
+/// `options.normalize_types`
+pub(super) fn type_column(headers: &csv_async::ByteRecord, options: &Options) -> Option<usize> {
+    if !options.normalize_types {
+        return None;
+    }
+    headers.iter().position(|column| column == b"type")
+}
+
+/// Rewrites the type at `column` of `record` to its canonical spelling when it's a known alias
+pub(super) fn normalize_type(record: &mut csv_async::ByteRecord, column: usize) {
+    let r#type = record
+        .get(column)
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .and_then(TransactionType::from_alias);
+    if let Some(r#type) = r#type {
+        replace_field(record, column, r#type.name().as_bytes());
+    }
+}
+
+/// Index of the `amount` column in `headers` when its values are to be rewritten with
+/// [`normalize_amount`]
+pub(super) fn amount_column(headers: &csv_async::ByteRecord, options: &Options) -> Option<usize> {
+    if !options.rewrites_amounts() {
+        return None;
+    }
+    headers.iter().position(|column| column == b"amount")
+}
+
+/// Rewrites the amount at `column` of `record` without its `options.currency_symbol` and with
+/// `options.decimal_separator` swapped with `.`, so an amount using `.` no longer parses
+pub(super) fn normalize_amount(
+    record: &mut csv_async::ByteRecord,
+    column: usize,
+    options: &Options,
+) {
+    let Some(amount) = record
+        .get(column)
+        .and_then(|amount| std::str::from_utf8(amount).ok())
+    else {
+        return;
+    };
+    let mut amount = amount.trim();
+    if let Some(symbol) = options.currency_symbol.as_deref() {
+        amount = amount
+            .strip_prefix(symbol)
+            .or_else(|| amount.strip_suffix(symbol))
+            .unwrap_or(amount)
+            .trim();
+    }
+    let amount = match options.decimal_separator {
+        Some(separator) if separator != '.' => amount
+            .chars()
+            .map(|c| match c {
+                '.' => separator,
+                c if c == separator => '.',
+                c => c,
+            })
+            .collect(),
+        _ => amount.to_string(),
+    };
+    replace_field(record, column, amount.as_bytes());
+}
+
+fn replace_field(record: &mut csv_async::ByteRecord, column: usize, value: &[u8]) {
+    let replaced = record
+        .iter()
+        .enumerate()
+        .map(|(index, field)| if index == column { value } else { field })
+        .collect();
+    *record = replaced;
+}
+
+/// Applies a deserialized row, skipping it when invalid. I/O errors stop the run.
+pub(super) fn process_row<T: Write>(
+    processor: &mut Processor<T>,
+    row: csv_async::Result<Transaction>,
+) -> anyhow::Result<()> {
+    match row {
+        Ok(transaction) => processor.apply(transaction),
+        Err(e) if e.is_io_error() => Err(e.into()),
+        Err(e) => processor.skip(e),
+    }
+}
+
+/// Key of the client affected by `transaction`.
+/// Disputes, resolves and chargebacks may omit the currency, it then comes from the
+/// transaction they refer to.
+pub(super) fn client_key(
+    transaction: &Transaction,
+    past_transactions: &impl Store<TxId, Transaction>,
+) -> ClientKey {
+    let currency = match transaction.r#type {
+        TransactionType::Deposit | TransactionType::Widthdrawal | TransactionType::Custom(_) => {
+            transaction.currency.clone()
+        }
+        _ => transaction.currency.clone().or_else(|| {
+            past_transactions
+                .get(&transaction.tx)
+                .and_then(|past_transaction| past_transaction.currency.clone())
+        }),
+    };
+    (transaction.client, currency)
+}
+
+pub(super) fn parse_single_transaction<C, T>(
+    transaction: &Transaction,
+    clients: &mut C,
+    past_transactions: &mut T,
+    disputed_transactions: &mut T,
+    options: &Options,
+    notices: &mut Vec<Notice>,
+) -> anyhow::Result<Outcome>
+where
+    C: Store<ClientKey, Client>,
+    T: Store<TxId, Transaction>,
+{
+    let validation = transaction
+        .validate()
+        .and_then(|_| match options.max_decimals {
+            Some(places) => transaction.check_decimals(places),
+            None => Ok(()),
+        })
+        .and_then(|_| match options.max_amount {
+            Some(max) => transaction.check_max_amount(max),
+            None => Ok(()),
+        })
+        .and_then(|_| match options.expect_client_max {
+            Some(max) => transaction.check_client_max(max),
+            None => Ok(()),
+        });
+    if let Err(e) = validation {
+        diagnostic!(options, "Can't apply tx {}, {}", transaction.tx, e);
+        return Ok(Outcome::Rejected(Reason::from(&e)));
+    }
+    let rounded;
+    let transaction = match options.round_on_apply {
+        Some(places) => {
+            let places = transaction
+                .currency
+                .as_deref()
+                .and_then(|currency| options.currency_scale(currency))
+                .unwrap_or(places);
+            rounded = transaction.rounded(places);
+            &rounded
+        }
+        None => transaction,
+    };
+
+    let key = client_key(transaction, past_transactions);
+
+    let mut client = match clients.get(&key) {
+        Some(client) => client,
+        None => {
+            if let Some(limit) = options.limit_clients {
+                if clients.len() >= limit {
+                    bail!(
+                        "Too many clients, tx {} would create client {} over --limit-clients {}",
+                        transaction.tx,
+                        transaction.client,
+                        limit
+                    );
+                }
+            }
+            let client = Client {
+                id: transaction.client,
+                currency: key.1.clone(),
+                ..Default::default()
+            };
+            clients.insert(key.clone(), client.clone());
+            client
+        }
+    };
+
+    let outcome;
+    match transaction.r#type {
+        TransactionType::Deposit | TransactionType::Widthdrawal
+            if past_transactions.contains_key(&transaction.tx) =>
+        {
+            // Disputes refer to transactions by id, which must stay unique
+            diagnostic!(
+                options,
+                "Can't apply tx {} for client {}, the tx id is already used",
+                transaction.tx,
+                client.id
+            );
+            outcome = Outcome::Rejected(Reason::Duplicate);
+        }
+        TransactionType::Deposit => {
+            let amount = transaction.amount_or_error()?;
+            match adjust(&client, transaction, amount, Decimal::ZERO, amount, notices) {
+                Some(adjusted) => {
+                    client = adjusted;
+                    client.gross_deposits = client.gross_deposits.saturating_add(amount);
+                    outcome = Outcome::Applied;
+                    past_transactions.insert(transaction.tx, transaction.clone());
+                }
+                None => outcome = overflow(options, "deposit", transaction, &client),
+            }
+        }
+        TransactionType::Widthdrawal => {
+            let amount = transaction.amount_or_error()?;
+            let tolerance = options
+                .deny_withdrawal_overdraft_epsilon
+                .unwrap_or_default();
+            if client.available.saturating_add(tolerance) < amount {
+                diagnostic!(
+                    options,
+                    "Can't widthdraw amount {} for client {}, not enough fund",
+                    amount,
+                    client.id
+                );
+                outcome = Outcome::Rejected(Reason::InsufficientFunds);
+            } else {
+                match adjust(
+                    &client,
+                    transaction,
+                    -amount,
+                    Decimal::ZERO,
+                    -amount,
+                    notices,
+                ) {
+                    Some(adjusted) => {
+                        client = adjusted;
+                        client.gross_withdrawals = client.gross_withdrawals.saturating_add(amount);
+                        outcome = Outcome::Applied;
+                        past_transactions.insert(transaction.tx, transaction.clone());
+                    }
+                    None => outcome = overflow(options, "widthdraw", transaction, &client),
+                }
+            }
+        }
+        TransactionType::Dispute if client.locked => {
+            // A frozen account doesn't get new holds
+            diagnostic!(
+                options,
+                "Can't dispute tx {} for client {}, account is locked",
+                transaction.tx,
+                client.id
+            );
+            outcome = Outcome::Rejected(Reason::Locked);
+        }
+        TransactionType::Dispute => match past_transactions.get(&transaction.tx) {
+            None => {
+                diagnostic!(
+                    options,
+                    "Can't dispute tx {} for client {}, non-existing transaction",
+                    transaction.tx,
+                    client.id
+                );
+                outcome = Outcome::Rejected(Reason::UnknownTx);
+            }
+            Some(past_transaction) if past_transaction.client != client.id => {
+                let e = TransactionError::ClientMismatch {
+                    tx: transaction.tx,
+                    owner: past_transaction.client,
+                };
+                diagnostic!(
+                    options,
+                    "Can't dispute tx {} for client {}, {}",
+                    transaction.tx,
+                    client.id,
+                    e
+                );
+                outcome = Outcome::Rejected(Reason::from(&e));
+            }
+            Some(past_transaction) => match past_transaction.r#type {
+                TransactionType::Deposit => {
+                    let amount = past_transaction.amount_or_error()?;
+
+                    if client.available < amount && options.account_policy == AccountPolicy::Strict
+                    {
+                        diagnostic!(
+                            options,
+                            "Can't dispute amount {} for client {}, not enough fund",
+                            amount,
+                            client.id
+                        );
+                        outcome = Outcome::Rejected(Reason::InsufficientFunds);
+                    } else {
+                        match adjust(
+                            &client,
+                            transaction,
+                            -amount,
+                            amount,
+                            Decimal::ZERO,
+                            notices,
+                        ) {
+                            Some(adjusted) => {
+                                client = adjusted;
+                                disputed_transactions
+                                    .insert(past_transaction.tx, past_transaction.clone());
+                                outcome = Outcome::Applied;
+                            }
+                            None => outcome = overflow(options, "dispute", transaction, &client),
+                        }
+                    }
+                }
+                TransactionType::Widthdrawal if options.dispute_withdrawals => {
+                    // The withdrawn amount is held back until the dispute is settled
+                    let amount = past_transaction.amount_or_error()?;
+
+                    match adjust(&client, transaction, Decimal::ZERO, amount, amount, notices) {
+                        Some(adjusted) => {
+                            client = adjusted;
+                            disputed_transactions
+                                .insert(past_transaction.tx, past_transaction.clone());
+                            outcome = Outcome::Applied;
+                        }
+                        None => outcome = overflow(options, "dispute", transaction, &client),
+                    }
+                }
+                TransactionType::Widthdrawal => {
+                    diagnostic!(
+                        options,
+                        "Can't dispute tx {} for client {}, withdrawal disputes aren't enabled",
+                        past_transaction.tx,
+                        client.id
+                    );
+                    outcome = Outcome::Rejected(Reason::WithdrawalNotDisputable);
+                }
+                _ => {
+                    diagnostic!(
+                        options,
+                        "Can't dispute tx {} for client {}, isn't a deposit tx",
+                        past_transaction.tx,
+                        client.id
+                    );
+                    outcome = Outcome::Rejected(Reason::NotDeposit);
+                }
+            },
+        },
+        TransactionType::Resolve => {
+            match find_disputed(
+                disputed_transactions,
+                transaction.tx,
+                client.id,
+                transaction.amount,
+            ) {
+                Err(e) => {
+                    diagnostic!(
+                        options,
+                        "Can't resolve tx {} for client {}, {}",
+                        transaction.tx,
+                        client.id,
+                        e
+                    );
+                    outcome = Outcome::Rejected(Reason::from(&e));
+                }
+                Ok(disputed_transaction) => {
+                    let amount = disputed_transaction.amount_or_error()?;
+
+                    let (available, total) =
+                        if disputed_transaction.r#type == TransactionType::Widthdrawal {
+                            // The withdrawal stands
+                            (Decimal::ZERO, -amount)
+                        } else {
+                            (amount, Decimal::ZERO)
+                        };
+                    match adjust(&client, transaction, available, -amount, total, notices) {
+                        Some(adjusted) => {
+                            client = adjusted;
+                            disputed_transactions.remove(&transaction.tx);
+                            outcome = Outcome::Applied;
+                        }
+                        None => outcome = overflow(options, "resolve", transaction, &client),
+                    }
+                }
+            }
+        }
+        TransactionType::Chargeback => {
+            match find_disputed(
+                disputed_transactions,
+                transaction.tx,
+                client.id,
+                transaction.amount,
+            ) {
+                Err(e) => {
+                    diagnostic!(
+                        options,
+                        "Can't chargeback tx {} for client {}, {}",
+                        transaction.tx,
+                        client.id,
+                        e
+                    );
+                    outcome = Outcome::Rejected(Reason::from(&e));
+                }
+                Ok(disputed_transaction) => {
+                    let amount = disputed_transaction.amount_or_error()?;
+
+                    let (available, total) =
+                        if disputed_transaction.r#type == TransactionType::Widthdrawal {
+                            // The withdrawal is reversed, crediting the client
+                            (amount, Decimal::ZERO)
+                        } else {
+                            // Held and total go down together so `total == available + held`
+                            // holds. If the deposit was spent before its dispute, which only
+                            // `--account-policy allow-negative` allows, total ends negative: the
+                            // loss of the chargeback.
+                            (Decimal::ZERO, -amount)
+                        };
+                    match adjust(&client, transaction, available, -amount, total, notices) {
+                        Some(adjusted) => {
+                            client = adjusted;
+                            client.locked = true;
+                            disputed_transactions.remove(&transaction.tx);
+                            outcome = Outcome::Applied;
+                        }
+                        None => outcome = overflow(options, "chargeback", transaction, &client),
+                    }
+                }
+            }
+        }
+        TransactionType::Custom(ref name) => match options.handlers.get(name) {
+            Some(handler) => {
+                let mut updated = client.clone();
+                outcome = handler.apply(transaction, &mut updated);
+                if outcome.is_applied() {
+                    client = updated;
+                }
+            }
+            None => {
+                // Rows are checked against the handlers as they're read, see `check_schema`
+                diagnostic!(
+                    options,
+                    "Can't apply tx {} for client {}, unknown type {}",
+                    transaction.tx,
+                    client.id,
+                    name
+                );
+                outcome = Outcome::Rejected(Reason::InvalidRow);
+            }
+        },
+    }
+
+    if outcome.is_applied() {
+        client.active = true;
+    }
+
+    // Debug trace of every row, `--trace-state` being its JSON counterpart
+    diagnostic!(options, "Transaction: {:?} {:?}", transaction, outcome);
+    diagnostic!(options, "Client: {:?}", client);
+    clients.insert(key, client);
+    Ok(outcome)
+}
+
+/// Copy of `client` with the given amounts added to its balances, `None` if one of them would
+/// overflow. `Decimal` holds up to 28 significant digits, a balance reaching that limit is
+/// rounded, which is reported once in `notices`.
+fn adjust(
+    client: &Client,
+    transaction: &Transaction,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    notices: &mut Vec<Notice>,
+) -> Option<Client> {
+    let mut adjusted = client.clone();
+    let mut lost = None;
+    for (balance, amount) in [
+        (&mut adjusted.total, total),
+        (&mut adjusted.available, available),
+        (&mut adjusted.held, held),
+    ] {
+        // Left as is, adding zero may change the scale of the balance
+        if amount.is_zero() {
+            continue;
+        }
+        let (sum, rounded) = accumulate(*balance, amount)?;
+        if rounded && lost.is_none() {
+            lost = Some(precision_lost(transaction, *balance, amount, sum));
+        }
+        *balance = sum;
+    }
+    notices.extend(lost);
+    Some(adjusted)
+}
+
+/// Adds `amount` to `balance`, `None` if the sum overflows, along with whether it was rounded
+fn accumulate(balance: Decimal, amount: Decimal) -> Option<(Decimal, bool)> {
+    let sum = balance.checked_add(amount)?;
+    Some((sum, sum - balance != amount))
+}
+
+/// Rejects `transaction`, which would overflow a balance of `client`
+fn overflow(
+    options: &Options,
+    action: &str,
+    transaction: &Transaction,
+    client: &Client,
+) -> Outcome {
+    diagnostic!(
+        options,
+        "Can't {} tx {} for client {}, the balance would overflow",
+        action,
+        transaction.tx,
+        client.id
+    );
+    Outcome::Rejected(Reason::Overflow)
+}
+
+/// Notice of `transaction` adding `amount` to `balance` being rounded to `sum`
+fn precision_lost(
+    transaction: &Transaction,
+    balance: Decimal,
+    amount: Decimal,
+    sum: Decimal,
+) -> Notice {
+    Notice {
+        tx: Some(transaction.tx),
+        client: Some(transaction.client),
+        ..Notice::new(
+            NoticeReason::PrecisionLost,
+            format!(
+                "Precision lost by tx {}, {} + {} was rounded to {}",
+                transaction.tx, balance, amount, sum
+            ),
+        )
+    }
+}
+
+/// Looks up the disputed transaction `tx`, which must belong to `client` and have the `amount`
+/// of the resolve or chargeback, if it gives one
+fn find_disputed(
+    disputed_transactions: &impl Store<TxId, Transaction>,
+    tx: TxId,
+    client: ClientId,
+    amount: Option<Decimal>,
+) -> Result<Transaction, TransactionError> {
+    let disputed_transaction = disputed_transactions
+        .get(&tx)
+        .ok_or(TransactionError::NotDisputed(tx))?;
+    if disputed_transaction.client != client {
+        return Err(TransactionError::ClientMismatch {
+            tx,
+            owner: disputed_transaction.client,
+        });
+    }
+    if let (Some(amount), Some(disputed)) = (amount, disputed_transaction.amount) {
+        if amount != disputed {
+            return Err(TransactionError::AmountMismatch {
+                tx,
+                amount,
+                disputed,
+            });
+        }
+    }
+    Ok(disputed_transaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::transaction::TransactionBuilder;
+    use crate::options::{AmountUnit, SortedTx};
+    use assertor::*;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Engine state of a test, applying transactions with `parse_single_transaction`
+    #[derive(Default)]
+    struct TestEngine {
+        clients: ClientHash,
+        past_transactions: TransactionHash,
+        disputed_transactions: TransactionHash,
+        options: Options,
+    }
+
+    /// Shorthands applying transactions, e.g. `test_context.deposit(1, 1, dec!(2))?`, other
+    /// transactions being built with a `TransactionBuilder`
+    impl TestEngine {
+        fn apply(&mut self, transaction: TransactionBuilder) -> anyhow::Result<Outcome> {
+            parse_single_transaction(
+                &transaction.build(),
+                &mut self.clients,
+                &mut self.past_transactions,
+                &mut self.disputed_transactions,
+                &self.options,
+                &mut vec![],
+            )
+        }
+
+        /// Applies `steps` in order, checking whether each one went through
+        fn apply_steps(
+            &mut self,
+            steps: impl IntoIterator<Item = (TransactionBuilder, bool)>,
+        ) -> anyhow::Result<()> {
+            for (transaction, applied) in steps {
+                let outcome = self.apply(transaction)?;
+                assert_that!(outcome.is_applied()).is_equal_to(applied);
+            }
+            Ok(())
+        }
+
+        fn deposit(&mut self, client: u16, tx: u32, amount: Decimal) -> anyhow::Result<Outcome> {
+            self.apply(TransactionBuilder::deposit(client, tx, amount))
+        }
+
+        fn withdraw(&mut self, client: u16, tx: u32, amount: Decimal) -> anyhow::Result<Outcome> {
+            self.apply(TransactionBuilder::withdrawal(client, tx, amount))
+        }
+
+        fn dispute(&mut self, client: u16, tx: u32) -> anyhow::Result<Outcome> {
+            self.apply(TransactionBuilder::dispute(client, tx))
+        }
+
+        fn resolve(&mut self, client: u16, tx: u32) -> anyhow::Result<Outcome> {
+            self.apply(TransactionBuilder::resolve(client, tx))
+        }
+
+        fn chargeback(&mut self, client: u16, tx: u32) -> anyhow::Result<Outcome> {
+            self.apply(TransactionBuilder::chargeback(client, tx))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deposits_one() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(2.0))?;
+        assert!(outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(2.0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(2.0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].locked).is_equal_to(false);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(1);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deposits_two() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(2.0))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.deposit(1, 2, dec!(5.890))?;
+        assert!(outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)]).is_equal_to(Client {
+            id: ClientId(1),
+            available: dec!(7.89),
+            held: dec!(0),
+            total: dec!(7.89),
+            locked: false,
+            currency: None,
+            gross_deposits: dec!(7.89),
+            gross_withdrawals: dec!(0),
+            active: true,
+        });
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_widthdrawal_enough_fund() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.withdraw(1, 2, dec!(10.001))?;
+        assert!(outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(10.1224));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(10.1224));
+        assert_that!(test_context.clients[&(ClientId(1), None)].locked).is_equal_to(false);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_widthdrawal_not_enough_fund() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.withdraw(1, 2, dec!(20.12345))?;
+        assert!(!outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(20.1234));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(20.1234));
+        assert_that!(test_context.clients[&(ClientId(1), None)].locked).is_equal_to(false);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(1);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispute_tx_exists() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.deposit(1, 2, dec!(1.123))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.dispute(1, 2)?;
+        assert!(outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(20.1234));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].locked).is_equal_to(false);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispute_tx_does_not_exist() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.deposit(1, 2, dec!(1.123))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.dispute(1, 3)?;
+        assert!(!outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].locked).is_equal_to(false);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_tx_exists() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.deposit(1, 2, dec!(1.123))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.dispute(1, 2)?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.resolve(1, 2)?;
+        assert!(outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].locked).is_equal_to(false);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_tx_does_not_exist() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.deposit(1, 2, dec!(1.123))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.dispute(1, 3)?;
+        assert!(!outcome.is_applied());
+
+        let outcome = test_context.resolve(1, 3)?;
+        assert!(!outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].locked).is_equal_to(false);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_chargeback_exists() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.deposit(1, 2, dec!(1.123))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.dispute(1, 2)?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.chargeback(1, 2)?;
+        assert!(outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)]).is_equal_to(Client {
+            id: ClientId(1),
+            available: dec!(20.1234),
+            held: dec!(0),
+            total: dec!(20.1234),
+            locked: true,
+            currency: None,
+            gross_deposits: dec!(21.2464),
+            gross_withdrawals: dec!(0),
+            active: true,
+        });
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_chargeback_does_not_exist() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        let outcome = test_context.deposit(1, 1, dec!(20.1234))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.deposit(1, 2, dec!(1.123))?;
+        assert!(outcome.is_applied());
+
+        let outcome = test_context.resolve(1, 3)?;
+        assert!(!outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total)
+            .is_equal_to(dec!(20.1234) + dec!(1.123));
+        assert!(!test_context.clients[&(ClientId(1), None)].locked);
+        assert_that!(test_context.clients).has_length(1);
+        assert_that!(test_context.past_transactions).has_length(2);
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispute_corrupted_past_transaction() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.past_transactions.insert(
+            TxId(1),
+            // Without its amount
+            TransactionBuilder::new(TransactionType::Deposit, 1, 1).build(),
+        );
+
+        let result = test_context.dispute(1, 1);
+        assert_that!(result.unwrap_err().downcast::<TransactionError>()?)
+            .is_equal_to(TransactionError::MissingAmount(TxId(1)));
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_row_is_skipped() -> anyhow::Result<()> {
+        let input: &[u8] =
+            b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,\xff2.0\ndeposit,1,3,3.0\n";
+        let mut trace = vec![];
+        let clients = parse_transactions(input, &Options::default(), &mut trace).await?;
+
+        let trace = String::from_utf8(trace)?;
+        assert_that!(trace).starts_with("Skipping invalid row: ");
+        assert_that!(trace).contains("invalid utf-8");
+        assert_that!(trace.lines().count()).is_equal_to(1);
+        assert_that!(clients).has_length(1);
+        assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(4.0));
+        assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(4.0));
+
+        Ok(())
+    }
+
+    const LOCKED_AND_UNLOCKED: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.5
+deposit,2,2,2.0
+dispute,2,2,
+chargeback,2,2,
+";
+
+    #[tokio::test]
+    async fn test_output_locked_only() -> anyhow::Result<()> {
+        let clients =
+            parse_transactions(LOCKED_AND_UNLOCKED, &Options::default(), &mut io::sink()).await?;
+        let options = Options {
+            output_locked_only: true,
+            ..Default::default()
+        };
+        let output = write_clients(&clients, &options, vec![]).await?;
+
+        assert_that!(String::from_utf8(output)?)
+            .is_equal_to("client,available,held,total,locked\n2,0,0,0,true\n".to_string());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exclude_locked() -> anyhow::Result<()> {
+        let clients =
+            parse_transactions(LOCKED_AND_UNLOCKED, &Options::default(), &mut io::sink()).await?;
+        let options = Options {
+            exclude_locked: true,
+            ..Default::default()
+        };
+        let output = write_clients(&clients, &options, vec![]).await?;
+
+        assert_that!(String::from_utf8(output)?)
+            .is_equal_to("client,available,held,total,locked\n1,1.5,0,1.5,false\n".to_string());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_omit_inactive() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.5
+widthdrawal,2,2,2.0
+";
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+        assert_that!(clients).has_length(2);
+
+        let options = Options {
+            omit_inactive: true,
+            ..Default::default()
+        };
+        let output = write_clients(&clients, &options, vec![]).await?;
+
+        assert_that!(String::from_utf8(output)?)
+            .is_equal_to("client,available,held,total,locked\n1,1.5,0,1.5,false\n".to_string());
+
+        // Reported once, however many times the clients are written
+        let mut trace = vec![];
+        report_inactive(&clients, &options, &mut trace)?;
+        assert_that!(String::from_utf8(trace)?).is_equal_to(
+            "Leaving out client 2, none of its transactions went through\n".to_string(),
+        );
+        let mut trace = vec![];
+        report_inactive(&clients, &Options::default(), &mut trace)?;
+        assert!(trace.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_rounded_to_currency_scale() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount,currency
+deposit,1,1,10.456,USD
+deposit,2,2,1234.6,JPY
+deposit,3,3,0.123456789,BTC
+";
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+
+        let output = write_clients(&clients, &Options::default(), vec![]).await?;
+        assert_that!(String::from_utf8(output)?).is_equal_to(
+            "client,available,held,total,locked,currency
+1,10.46,0,10.46,false,USD
+2,1235,0,1235,false,JPY
+3,0.12345679,0,0.12345679,false,BTC
+"
+            .to_string(),
+        );
+
+        let options = Options {
+            currency_scale: vec!["USD=1".parse()?, "JPY=1".parse()?],
+            ..Default::default()
+        };
+        let output = write_clients(&clients, &options, vec![]).await?;
+        assert_that!(String::from_utf8(output)?).is_equal_to(
+            "client,available,held,total,locked,currency
+1,10.5,0,10.5,false,USD
+2,1234.6,0,1234.6,false,JPY
+3,0.12345679,0,0.12345679,false,BTC
+"
+            .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cents_columns() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.5
+";
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+        let options = Options {
+            cents_columns: true,
+            ..Default::default()
+        };
+        let output = write_clients(&clients, &options, vec![]).await?;
+
+        assert_that!(String::from_utf8(output)?).is_equal_to(
+            "client,available,held,total,locked,available_cents,held_cents,total_cents
+1,10.5,0,10.5,false,1050,0,1050
+"
+            .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cents_columns_overflow() -> anyhow::Result<()> {
+        let mut clients = ClientHash::new();
+        clients.insert(
+            (ClientId(1), None),
+            Client {
+                id: ClientId(1),
+                available: Decimal::MAX,
+                total: Decimal::MAX,
+                ..Default::default()
+            },
+        );
+        let options = Options {
+            cents_columns: true,
+            ..Default::default()
+        };
+        let error = write_clients(&clients, &options, vec![]).await.unwrap_err();
+        assert_that!(error.to_string()).is_equal_to(format!(
+            "amount {} of client 1 is too large to be written in cents",
+            Decimal::MAX
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_header_comment() -> anyhow::Result<()> {
+        let clients =
+            parse_transactions(LOCKED_AND_UNLOCKED, &Options::default(), &mut io::sink()).await?;
+        let output =
+            String::from_utf8(write_clients(&clients, &Options::default(), vec![]).await?)?;
+        assert!(!output.contains('#'));
+
+        let options = Options {
+            header_comment: true,
+            ..Default::default()
+        };
+        let commented = String::from_utf8(write_clients(&clients, &options, vec![]).await?)?;
+        let (comment, rest) = commented.split_once('\n').unwrap();
+        assert!(comment.starts_with(&format!(
+            "# ex {}, generated at ",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert_that!(rest).is_equal_to(output.as_str());
+        // Doesn't change the checksum
+        assert_that!(checksum(&clients, &options).await?)
+            .is_equal_to(checksum(&clients, &Options::default()).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checksum() -> anyhow::Result<()> {
+        let clients =
+            parse_transactions(LOCKED_AND_UNLOCKED, &Options::default(), &mut io::sink()).await?;
+        let checksum_of = |clients| async move { checksum(&clients, &Options::default()).await };
+
+        let expected = checksum_of(clients.clone()).await?;
+        assert_that!(expected.len()).is_equal_to(64);
+        let again =
+            parse_transactions(LOCKED_AND_UNLOCKED, &Options::default(), &mut io::sink()).await?;
+        assert_that!(checksum_of(again).await?).is_equal_to(expected.clone());
+        // The output order doesn't matter
+        let options = Options {
+            sort_by: SortBy::Total,
+            ..Default::default()
+        };
+        assert_that!(checksum(&clients, &options).await?).is_equal_to(expected.clone());
+
+        let changed = b"type,client,tx,amount
+deposit,1,1,1.5
+deposit,2,2,2.0
+dispute,2,2,
+resolve,2,2,
+";
+        let changed =
+            parse_transactions(&changed[..], &Options::default(), &mut io::sink()).await?;
+        assert_that!(checksum_of(changed).await?).is_not_equal_to(expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_currencies_tracked_separately() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount,currency
+deposit,1,1,10.0,USD
+deposit,1,2,5.0,EUR
+deposit,1,3,2.0,USD
+dispute,1,2,,
+";
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+
+        assert_that!(clients).has_length(2);
+        let usd = &clients[&(ClientId(1), Some("USD".to_string()))];
+        assert_that!(usd.available).is_equal_to(dec!(12.0));
+        assert_that!(usd.held).is_equal_to(dec!(0));
+        assert_that!(usd.total).is_equal_to(dec!(12.0));
+        let eur = &clients[&(ClientId(1), Some("EUR".to_string()))];
+        assert_that!(eur.available).is_equal_to(dec!(0));
+        assert_that!(eur.held).is_equal_to(dec!(5.0));
+        assert_that!(eur.total).is_equal_to(dec!(5.0));
+
+        let output =
+            String::from_utf8(write_clients(&clients, &Options::default(), vec![]).await?)?;
+        assert!(output.starts_with("client,available,held,total,locked,currency\n"));
+        assert!(output.contains("1,12,0,12,false,USD\n"));
+        assert!(output.contains("1,0,5,5,false,EUR\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_amount_is_skipped() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,99999999999999999999999999999999999999.0
+deposit,1,3,2.0
+";
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+
+        assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(3.0));
+        assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(3.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_garbage_amounts_are_skipped() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,abc
+deposit,1,3,2.0
+widthdrawal,1,4,1.0.0
+deposit,1,5,-
+deposit,1,6,0.5
+widthdrawal,1,7,1e3
+";
+        for read_ahead in [0, 2] {
+            let options = Options {
+                read_ahead,
+                ..Default::default()
+            };
+            let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+
+            assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(3.5));
+            assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(3.5));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transactions_are_rejected() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,0
+widthdrawal,1,3,-1.0
+deposit,1,4,
+dispute,1,1,1.0
+";
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+
+        assert_that!(clients).has_length(1);
+        assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(1.0));
+        assert_that!(clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deposits_near_precision_limit() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,79228162514264337593543950000
+deposit,1,2,0.5
+deposit,1,3,1000
+deposit,1,4,300
+deposit,2,5,79228162514264337593543900000
+deposit,2,6,10000
+dispute,2,6,
+deposit,2,7,50000.5
+resolve,2,6,
+";
+        let options = Options {
+            log_format: LogFormat::Json,
+            ..Default::default()
+        };
+        let mut trace = vec![];
+        let clients = parse_transactions(input, &options, &mut trace).await?;
+
+        // The fraction is rounded away, the next deposit would overflow
+        assert_that!(clients[&(ClientId(1), None)].total)
+            .is_equal_to(dec!(79228162514264337593543950300));
+        // The rounding is reported once, though both the total and the available funds are
+        // rounded. The total of client 2 would overflow, its available funds being rounded
+        // isn't reported as the deposit is rejected.
+        let precision_lost = r#"{"row":2,"tx":2,"client":1,"reason":"precision_lost","message":"Precision lost by tx 2, 79228162514264337593543950000 + 0.5 was rounded to 79228162514264337593543950000"}"#;
+        assert_that!(String::from_utf8(trace)?).is_equal_to(format!(
+            "{}\n{}\n{}\n",
+            precision_lost,
+            r#"{"row":3,"tx":3,"client":1,"reason":"overflow"}"#,
+            r#"{"row":8,"tx":7,"client":2,"reason":"overflow"}"#
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_summary() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.5
+deposit,2,2,2.0
+dispute,2,2,
+chargeback,2,2,
+widthdrawal,1,3,5.0
+deposit,1,4,abc
+deposit,3,5,1.0
+dispute,3,5,
+";
+        let engine = parse_into_engine(input, &Options::default(), &mut io::sink()).await?;
+
+        assert_that!(serde_json::to_string(&engine.summary())?).is_equal_to(
+            r#"{"clients":3,"locked":1,"held":"1","applied":6,"rejected":1,"skipped":1}"#
+                .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_amount_schema_error() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,2.0
+deposit,1,2,
+dispute,1,1,
+";
+        let options = Options {
+            log_format: LogFormat::Json,
+            ..Default::default()
+        };
+        let mut trace = vec![];
+        let engine = parse_into_engine(input, &options, &mut trace).await?;
+
+        assert_that!(String::from_utf8(trace)?.as_str()).is_equal_to(
+            r#"{"row":2,"tx":null,"client":null,"reason":"invalid_row","error":"amount required for deposit at row 2"}
+{"row":null,"tx":null,"client":null,"reason":"open_disputes","message":"1 disputes still open at the end of the input, their funds stay held"}
+"#,
+        );
+        assert_that!(engine.stats().skipped).is_equal_to(1);
+        assert_that!(engine.stats().applied).is_equal_to(2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_negative_available() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+widthdrawal,1,2,8.0
+dispute,1,1,
+deposit,2,3,1.0
+";
+        let options = Options {
+            account_policy: AccountPolicy::AllowNegative,
+            no_negative_available: true,
+            ..Default::default()
+        };
+        let mut trace = vec![];
+        let engine = parse_into_engine(input, &options, &mut trace).await?;
+        assert_that!(String::from_utf8(trace)?.as_str())
+            .is_equal_to("Warning: client 1 has negative available -8.0\n");
+        assert_that!(engine.summary().negative_available).is_equal_to(vec![ClientId(1)]);
+
+        let options = Options {
+            exclude_negative_available: true,
+            ..options
+        };
+        let output = write_clients(engine.clients(), &options, vec![]).await?;
+        assert_that!(String::from_utf8(output)?.as_str()).is_equal_to(
+            "client,available,held,total,locked
+2,1,0,1,false
+",
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_normalize_types() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+Deposits,1,1,10.0
+withdraw,1,2,1.0
+withdrawals,1,3,2.0
+deposit,1,4,5.0
+disputes,1,4,
+resolves,1,4,
+dispute,1,4,
+chargebacks,1,4,
+";
+        // Only the canonical spellings go through by default
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(5.0));
+        assert_that!(clients[&(ClientId(1), None)].held).is_equal_to(dec!(5.0));
+
+        for read_ahead in [0, 2] {
+            let options = Options {
+                normalize_types: true,
+                read_ahead,
+                ..Default::default()
+            };
+            let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+
+            assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(7.0));
+            assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(7.0));
+            assert!(clients[&(ClientId(1), None)].locked);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_currency_symbol_and_decimal_separator() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,$10.00
+deposit,2,2,\"5,00\"
+deposit,2,3,\"1,50 $\"
+deposit,2,4,2.50
+";
+        for read_ahead in [0, 2] {
+            let options = Options {
+                currency_symbol: Some("$".to_string()),
+                read_ahead,
+                ..Default::default()
+            };
+            let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+            assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(10.00));
+            // 5,00 and 1,50 aren't amounts with a `.` separator
+            assert_that!(clients[&(ClientId(2), None)].total).is_equal_to(dec!(2.50));
+
+            let options = Options {
+                currency_symbol: Some("$".to_string()),
+                decimal_separator: Some(','),
+                read_ahead,
+                ..Default::default()
+            };
+            let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+            // $10.00 and 2.50 are now invalid rather than read as 1000 or 250
+            assert!(!clients.contains_key(&(ClientId(1), None)));
+            assert_that!(clients[&(ClientId(2), None)].total).is_equal_to(dec!(6.50));
+        }
+
+        let options = Options {
+            currency_symbol: Some("€".to_string()),
+            decimal_separator: Some(','),
+            ..Default::default()
+        };
+        let input = "type,client,tx,amount\ndeposit,1,1,\"€5,00\"\n";
+        let clients = parse_transactions(input.as_bytes(), &options, &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(5.00));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_since_tx_with_restored_maps() -> anyhow::Result<()> {
+        let first: &[u8] = b"type,client,tx,amount
+deposit,1,98,10.0
+deposit,2,99,5.0
+dispute,2,99,
+";
+        // Overlaps the first file from tx 99
+        let second: &[u8] = b"type,client,tx,amount
+deposit,2,99,5.0
+deposit,1,100,1.0
+widthdrawal,1,101,1.0
+dispute,1,98,
+resolve,2,99,
+";
+        let path = std::env::temp_dir().join(format!("ex-since-tx-{}.json", std::process::id()));
+        let options = Options {
+            dump_maps: Some(path.clone()),
+            ..Default::default()
+        };
+        parse_transactions(first, &options, &mut io::sink()).await?;
+
+        let options = Options {
+            restore_maps: Some(path.clone()),
+            since_tx: Some(100),
+            ..Default::default()
+        };
+        let engine = parse_into_engine(second, &options, &mut io::sink()).await;
+        std::fs::remove_file(&path)?;
+        let engine = engine?;
+
+        // Only tx 100 and 101 are new, the dispute and resolve refer to the restored history
+        let client = &engine.clients()[&(ClientId(1), None)];
+        assert_that!(client.available).is_equal_to(dec!(0));
+        assert_that!(client.held).is_equal_to(dec!(10.0));
+        assert_that!(client.total).is_equal_to(dec!(10.0));
+        let client = &engine.clients()[&(ClientId(2), None)];
+        assert_that!(client.available).is_equal_to(dec!(5.0));
+        assert_that!(client.held).is_equal_to(dec!(0));
+        assert_that!(engine.stats().applied).is_equal_to(4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_short_rows() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.0
+dispute,1,1
+resolve,1,1
+dispute,1,2
+deposit,1,3
+";
+        for read_ahead in [0, 2] {
+            let options = Options {
+                read_ahead,
+                ..Default::default()
+            };
+            let engine = parse_into_engine(input, &options, &mut io::sink()).await?;
+            let client = &engine.clients()[&(ClientId(1), None)];
+            assert_that!(client.available).is_equal_to(dec!(10.0));
+            assert_that!(client.held).is_equal_to(dec!(5.0));
+            // A deposit still requires its amount
+            assert_that!(engine.stats().skipped).is_equal_to(1);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_type_with_non_breaking_space() -> anyhow::Result<()> {
+        let input = "type,client,tx,amount
+deposit\u{a0},1,1,10.0
+\u{a0}dispute,1,1,
+";
+        let clients =
+            parse_transactions(input.as_bytes(), &Options::default(), &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].held).is_equal_to(dec!(10.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_held_threshold() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,50.0
+deposit,1,2,5000.0
+dispute,1,1,
+dispute,1,2,
+";
+        let options = Options {
+            held_threshold: Some(dec!(1000)),
+            ..Default::default()
+        };
+        let mut trace = vec![];
+        let clients = parse_transactions(input, &options, &mut trace).await?;
+
+        assert_that!(clients[&(ClientId(1), None)].held).is_equal_to(dec!(5050));
+        assert_that!(String::from_utf8(trace)?).is_equal_to(
+            "Warning: client 1 holds 5050.0 after tx 2, over --held-threshold 1000\n".to_string(),
+        );
+
+        let options = Options {
+            strict_held_threshold: true,
+            ..options
+        };
+        let error = parse_transactions(input, &options, &mut io::sink())
+            .await
+            .err()
+            .unwrap();
+        assert_that!(error.to_string()).is_equal_to(
+            "Client 1 holds 5050.0 after tx 2, over --held-threshold 1000".to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_require_sorted_tx() -> anyhow::Result<()> {
+        // Sorted per client but not over the whole input, disputes referring to earlier ids
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,3,1.0
+deposit,1,2,2.0
+dispute,1,1,
+widthdrawal,2,4,0.5
+";
+        let options = Options {
+            require_sorted_tx: Some(SortedTx::Client),
+            ..Default::default()
+        };
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].held).is_equal_to(dec!(5.0));
+        assert_that!(clients[&(ClientId(2), None)].total).is_equal_to(dec!(0.5));
+
+        let options = Options {
+            require_sorted_tx: Some(SortedTx::Global),
+            ..Default::default()
+        };
+        let error = parse_transactions(input, &options, &mut io::sink())
+            .await
+            .err()
+            .unwrap();
+        assert_that!(error.to_string()).is_equal_to(
+            "Row 3: tx 2 of client 1 comes after tx 3, unsorted for --require-sorted-tx"
+                .to_string(),
+        );
+
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,2,5.0
+deposit,1,2,2.0
+";
+        let options = Options {
+            require_sorted_tx: Some(SortedTx::Client),
+            ..Default::default()
+        };
+        assert!(parse_transactions(input, &options, &mut io::sink())
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_trace_state() -> anyhow::Result<()> {
+        let options = Options {
+            trace_state: true,
+            ..Default::default()
+        };
+        let mut trace = vec![];
+        parse_transactions(LOCKED_AND_UNLOCKED, &options, &mut trace).await?;
+
+        let trace = String::from_utf8(trace)?;
+        let snapshots = trace.lines().collect::<Vec<_>>();
+        assert_that!(snapshots).has_length(4);
+        assert_that!(snapshots[3].to_string()).is_equal_to(
+            r#"{"id":2,"available":"0.0","held":"0.0","total":"0.0","locked":true,"currency":null,"gross_deposits":"2.0","gross_withdrawals":"0"}"#
+                .to_string(),
+        );
+
+        Ok(())
+    }
+
+    /// Deposits, withdrawals and disputes over a handful of clients, spanning several
+    /// read-ahead chunks
+    fn generated_input(rows: u32) -> Vec<u8> {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for tx in 1..=rows {
+            let client = tx % 7;
+            let row = match tx % 5 {
+                0 => format!("widthdrawal,{},{},{}.25\n", client, tx, tx % 13),
+                3 => format!("dispute,{},{},\n", client, tx - 1),
+                4 if tx % 3 == 0 => format!("chargeback,{},{},\n", client, tx - 2),
+                4 => format!("resolve,{},{},\n", client, tx - 2),
+                _ => format!("deposit,{},{},{}.5\n", client, tx, tx % 17),
+            };
+            input.push_str(&row);
+        }
+        input.into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_matches_serial() -> anyhow::Result<()> {
+        let input = generated_input(10_000);
+
+        let serial =
+            parse_transactions(input.as_slice(), &Options::default(), &mut io::sink()).await?;
+        let options = Options {
+            read_ahead: 4,
+            ..Default::default()
+        };
+        let read_ahead = parse_transactions(input.as_slice(), &options, &mut io::sink()).await?;
+
+        assert_that!(serial).has_length(7);
+        assert_that!(read_ahead).is_equal_to(serial);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batched_matches_serial() -> anyhow::Result<()> {
+        let mut input = generated_input(10_000);
+        input.extend_from_slice(b"deposit,1,10001,abc\ndeposit,1,10002\ndeposit,1,10003,2.0\n");
+
+        // Rejected and skipped rows are logged to the trace, in order
+        let options = Options {
+            log_format: LogFormat::Json,
+            ..Default::default()
+        };
+        let mut serial_trace = vec![];
+        let serial = parse_transactions(input.as_slice(), &options, &mut serial_trace).await?;
+        for batch_size in [1, 3, 1024] {
+            let options = Options {
+                batch_size,
+                log_format: LogFormat::Json,
+                ..Default::default()
+            };
+            let mut trace = vec![];
+            let batched = parse_transactions(input.as_slice(), &options, &mut trace).await?;
+
+            assert_that!(batched).is_equal_to(serial.clone());
+            assert_that!(trace).is_equal_to(serial_trace.clone());
+        }
+
+        Ok(())
+    }
+
+    const EXTRA_COLUMN: &[u8] = b"type,client,tx,amount,comment
+deposit,1,1,1.0,first
+deposit,1,2,2.0,second
+";
+
+    #[tokio::test]
+    async fn test_extra_column_allowed_by_default() -> anyhow::Result<()> {
+        let clients =
+            parse_transactions(EXTRA_COLUMN, &Options::default(), &mut io::sink()).await?;
+
+        assert_that!(clients[&(ClientId(1), None)].total).is_equal_to(dec!(3.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extra_column_rejected_with_strict_columns() {
+        let options = Options {
+            strict_columns: true,
+            ..Default::default()
+        };
+        let result = parse_transactions(EXTRA_COLUMN, &options, &mut io::sink()).await;
+
+        assert_that!(result.unwrap_err().to_string())
+            .is_equal_to("Unexpected column comment in input".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_output_has_same_rows() -> anyhow::Result<()> {
+        let input = generated_input(5_000);
+
+        let clients =
+            parse_transactions(input.as_slice(), &Options::default(), &mut io::sink()).await?;
+        let sorted =
+            String::from_utf8(write_clients(&clients, &Options::default(), vec![]).await?)?;
+
+        let options = Options {
+            streaming_output: true,
+            flush_every: 2,
+            ..Default::default()
+        };
+        let streamed = String::from_utf8(write_clients(&clients, &options, vec![]).await?)?;
+
+        let mut sorted_rows = sorted.lines().collect::<Vec<_>>();
+        let mut streamed_rows = streamed.lines().collect::<Vec<_>>();
+        assert_that!(sorted_rows).has_length(8);
+        assert_that!(streamed_rows[0]).is_equal_to(sorted_rows[0]);
+        sorted_rows.sort_unstable();
+        streamed_rows.sort_unstable();
+        assert_that!(streamed_rows).is_equal_to(sorted_rows);
+
+        Ok(())
+    }
+
+    /// Sink keeping what it's written and counting the flushes
+    #[derive(Default)]
+    struct FlushCounter {
+        written: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for FlushCounter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            self.flushes += 1;
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streaming_output_flush_interval() -> anyhow::Result<()> {
+        let input = generated_input(5_000);
+        let clients =
+            parse_transactions(input.as_slice(), &Options::default(), &mut io::sink()).await?;
+        let expected = String::from_utf8(
+            write_clients(
+                &clients,
+                &Options {
+                    streaming_output: true,
+                    flush_every: 0,
+                    ..Default::default()
+                },
+                vec![],
+            )
+            .await?,
+        )?;
+
+        for (flush_every, flush_interval_ms) in [(0, Some(0)), (3, None), (3, Some(60_000))] {
+            let options = Options {
+                streaming_output: true,
+                flush_every,
+                flush_interval_ms,
+                ..Default::default()
+            };
+            let sink = write_clients(&clients, &options, FlushCounter::default()).await?;
+            assert_that!(String::from_utf8(sink.written)?).is_equal_to(expected.clone());
+            // Flushed after every client with a zero interval, else every 3 clients
+            let expected_flushes = if flush_every == 0 { 7 } else { 2 };
+            assert!(sink.flushes >= expected_flushes);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispute_takes_currency_of_deposit() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (
+                TransactionBuilder::deposit(1, 1, dec!(10)).currency("USD"),
+                true,
+            ),
+            (
+                TransactionBuilder::deposit(1, 2, dec!(5)).currency("EUR"),
+                true,
+            ),
+            (TransactionBuilder::dispute(1, 2), true),
+        ])?;
+
+        let usd = &test_context.clients[&(ClientId(1), Some("USD".to_string()))];
+        assert_that!((usd.available, usd.held)).is_equal_to((dec!(10), dec!(0)));
+        let eur = &test_context.clients[&(ClientId(1), Some("EUR".to_string()))];
+        assert_that!((eur.available, eur.held)).is_equal_to((dec!(0), dec!(5)));
+        assert!(!test_context.clients.contains_key(&(ClientId(1), None)));
+
+        Ok(())
+    }
+
+    /// Deposits tx 1 for client 1 and disputes it
+    fn disputed_deposit() -> anyhow::Result<TestEngine> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10.0)), true),
+            (TransactionBuilder::dispute(1, 1), true),
+        ])?;
+        Ok(test_context)
+    }
+
+    #[tokio::test]
+    async fn test_resolve_amount_mismatch() -> anyhow::Result<()> {
+        let mut test_context = disputed_deposit()?;
+        let outcome = test_context.apply(TransactionBuilder::resolve(1, 1).amount(dec!(9.99)))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::AmountMismatch));
+        let outcome = test_context.apply(TransactionBuilder::chargeback(1, 1).amount(dec!(100)))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::AmountMismatch));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(10.0));
+
+        // The disputed amount, whatever its scale
+        let outcome = test_context.apply(TransactionBuilder::resolve(1, 1).amount(dec!(10)))?;
+        assert!(outcome.is_applied());
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(10.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_client_mismatch() -> anyhow::Result<()> {
+        let mut test_context = disputed_deposit()?;
+        assert_that!(find_disputed(
+            &test_context.disputed_transactions,
+            TxId(1),
+            ClientId(2),
+            None
+        )
+        .unwrap_err())
+        .is_equal_to(TransactionError::ClientMismatch {
+            tx: TxId(1),
+            owner: ClientId(1),
+        });
+
+        let outcome = test_context.resolve(2, 1)?;
+        assert!(!outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(10.0));
+        assert_that!(test_context.clients[&(ClientId(2), None)].available).is_equal_to(dec!(0));
+        assert_that!(test_context.disputed_transactions).has_length(1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_client_mismatch() -> anyhow::Result<()> {
+        let mut test_context = disputed_deposit()?;
+
+        let outcome = test_context.chargeback(2, 1)?;
+        assert!(!outcome.is_applied());
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(10.0));
+        assert!(!test_context.clients[&(ClientId(1), None)].locked);
+        assert!(!test_context.clients[&(ClientId(2), None)].locked);
+        assert_that!(test_context.disputed_transactions).has_length(1);
+
+        Ok(())
+    }
+
+    /// Deposits 10, withdraws 8 then disputes the deposit under `account_policy`
+    fn dispute_spent_deposit(account_policy: AccountPolicy) -> anyhow::Result<TestEngine> {
+        let mut test_context = TestEngine {
+            options: Options {
+                account_policy,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        test_context.deposit(1, 1, dec!(10))?;
+        test_context.withdraw(1, 2, dec!(8))?;
+        test_context.dispute(1, 1)?;
+        Ok(test_context)
+    }
+
+    #[tokio::test]
+    async fn test_disputes_holding_over_max() -> anyhow::Result<()> {
+        let mut test_context = TestEngine {
+            options: Options {
+                account_policy: AccountPolicy::AllowNegative,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, Decimal::MAX), true),
+            (TransactionBuilder::withdrawal(1, 2, Decimal::MAX), true),
+            (TransactionBuilder::deposit(1, 3, Decimal::MAX), true),
+            (TransactionBuilder::dispute(1, 1), true),
+        ])?;
+
+        // Both deposits held would be over the maximum
+        let outcome = test_context.dispute(1, 3)?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::Overflow));
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert_that!((client.available, client.held, client.total)).is_equal_to((
+            dec!(0),
+            Decimal::MAX,
+            Decimal::MAX,
+        ));
+        assert_that!(test_context.disputed_transactions).has_length(1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispute_spent_deposit_strict() -> anyhow::Result<()> {
+        let test_context = dispute_spent_deposit(AccountPolicy::Strict)?;
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(2));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(2));
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispute_spent_deposit_allow_negative() -> anyhow::Result<()> {
+        let test_context = dispute_spent_deposit(AccountPolicy::AllowNegative)?;
+
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(-8));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(10));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(2));
+        assert_that!(test_context.disputed_transactions).has_length(1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_before_dispute() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(20.5)), true),
+            (TransactionBuilder::deposit(1, 2, dec!(4.25)), true),
+            (TransactionBuilder::resolve(1, 2), false),
+        ])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(24.75));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+
+        test_context.apply_steps([(TransactionBuilder::dispute(1, 2), true)])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(20.5));
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(4.25));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(24.75));
+
+        test_context.apply_steps([(TransactionBuilder::resolve(1, 2), true)])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)]).is_equal_to(Client {
+            id: ClientId(1),
+            available: dec!(24.75),
+            held: dec!(0),
+            total: dec!(24.75),
+            locked: false,
+            currency: None,
+            gross_deposits: dec!(24.75),
+            gross_withdrawals: dec!(0),
+            active: true,
+        });
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_before_dispute() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(20.5)), true),
+            (TransactionBuilder::deposit(1, 2, dec!(4.25)), true),
+            (TransactionBuilder::chargeback(1, 2), false),
+            (TransactionBuilder::dispute(1, 2), true),
+            (TransactionBuilder::chargeback(1, 2), true),
+        ])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)]).is_equal_to(Client {
+            id: ClientId(1),
+            available: dec!(20.5),
+            held: dec!(0),
+            total: dec!(20.5),
+            locked: true,
+            currency: None,
+            gross_deposits: dec!(24.75),
+            gross_withdrawals: dec!(0),
+            active: true,
+        });
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+widthdrawal,1,2,20.0
+deposit,1,3,oops
+dispute,1,4,
+dispute,1,1,
+resolve,2,1,
+";
+        let path = std::env::temp_dir().join(format!("ex-audit-{}.csv", std::process::id()));
+        let options = Options {
+            audit_log: Some(path.clone()),
+            ..Default::default()
+        };
+        parse_transactions(input, &options, &mut io::sink()).await?;
+
+        let audit = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_that!(audit.lines().map(String::from).collect::<Vec<_>>()).is_equal_to(vec![
+            "type,client,tx,amount,succeeded,reason".to_string(),
+            "deposit,1,1,10.0,true,".to_string(),
+            "widthdrawal,1,2,20.0,false,insufficient_funds".to_string(),
+            ",,,,false,invalid_row".to_string(),
+            "dispute,1,4,,false,unknown_tx".to_string(),
+            "dispute,1,1,,true,".to_string(),
+            "resolve,2,1,,false,client_mismatch".to_string(),
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_reusing_deposit_tx() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::withdrawal(1, 1, dec!(4)), false),
+            (TransactionBuilder::deposit(1, 1, dec!(4)), false),
+        ])?;
+        let outcome = test_context.withdraw(1, 1, dec!(4))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::Duplicate));
+
+        // The deposit can still be disputed
+        test_context.apply_steps([(TransactionBuilder::dispute(1, 1), true)])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(10));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(10));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_of_held_deposit() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.deposit(1, 1, dec!(10))?;
+        test_context.dispute(1, 1)?;
+        // The held funds can't be withdrawn during the dispute
+        let outcome = test_context.withdraw(1, 2, dec!(5))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::InsufficientFunds));
+        test_context.chargeback(1, 1)?;
+
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert_that!((client.available, client.held, client.total, client.locked)).is_equal_to((
+            dec!(0),
+            dec!(0),
+            dec!(0),
+            true,
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_of_spent_deposit() -> anyhow::Result<()> {
+        let mut test_context = TestEngine {
+            options: Options {
+                account_policy: AccountPolicy::AllowNegative,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        test_context.deposit(1, 1, dec!(10))?;
+        test_context.withdraw(1, 2, dec!(8))?;
+        assert!(test_context.dispute(1, 1)?.is_applied());
+        assert!(test_context.chargeback(1, 1)?.is_applied());
+
+        // The withdrawn 8 are lost
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert_that!((client.available, client.held, client.total, client.locked)).is_equal_to((
+            dec!(-8),
+            dec!(0),
+            dec!(-8),
+            true,
+        ));
+        assert_that!(client.available + client.held).is_equal_to(client.total);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_decimals() -> anyhow::Result<()> {
+        let mut test_context = TestEngine {
+            options: Options {
+                max_decimals: Some(4),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(test_context.deposit(1, 1, dec!(1.2345))?.is_applied());
+        assert!(test_context.deposit(1, 2, dec!(1.50000))?.is_applied());
+        let outcome = test_context.deposit(1, 3, dec!(1.23456))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::InvalidRow));
+        assert!(!test_context.withdraw(1, 4, dec!(0.00001))?.is_applied());
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(2.7345));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_amount() -> anyhow::Result<()> {
+        let mut test_context = TestEngine {
+            options: Options {
+                max_amount: Some(dec!(1000)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(test_context.deposit(1, 1, dec!(999.99))?.is_applied());
+        assert!(test_context.deposit(1, 2, dec!(1000))?.is_applied());
+        let outcome = test_context.deposit(1, 3, dec!(1000.01))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::InvalidRow));
+        assert!(!test_context.withdraw(1, 4, dec!(1500))?.is_applied());
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(1999.99));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expect_client_max_flags_swapped_columns() -> anyhow::Result<()> {
+        // client and tx swapped by the exporter
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.0
+deposit,2001,2,2.0
+deposit,2002,3,3.0
+";
+        let options = Options {
+            expect_client_max: Some(1000),
+            log_format: LogFormat::Json,
+            ..Default::default()
+        };
+        let mut trace = vec![];
+        let clients = parse_transactions(input, &options, &mut trace).await?;
+        assert_that!(clients.keys().collect::<Vec<_>>()).is_equal_to(vec![&(ClientId(1), None)]);
+        let trace = String::from_utf8(trace)?;
+        assert_that!(trace.lines().collect::<Vec<_>>()).is_equal_to(vec![
+            r#"{"row":2,"tx":2,"client":2001,"reason":"invalid_row"}"#,
+            r#"{"row":3,"tx":3,"client":2002,"reason":"invalid_row"}"#,
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_round_on_apply_leaves_no_dust() -> anyhow::Result<()> {
+        let mut dusty = TestEngine::default();
+        let mut rounded = TestEngine {
+            options: Options {
+                round_on_apply: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for test_context in [&mut dusty, &mut rounded] {
+            for tx in 0..100 {
+                assert!(test_context.deposit(1, 2 * tx, dec!(1.005))?.is_applied());
+                assert!(test_context
+                    .withdraw(1, 2 * tx + 1, dec!(1.00))?
+                    .is_applied());
+            }
+        }
+
+        assert_that!(dusty.clients[&(ClientId(1), None)].available).is_equal_to(dec!(0.5));
+        let client = &rounded.clients[&(ClientId(1), None)];
+        assert_that!(client.available).is_equal_to(dec!(0));
+        assert_that!(client.total).is_equal_to(dec!(0));
+        // Disputes hold the rounded amount
+        assert_that!(rounded.past_transactions[&TxId(0)].amount).is_equal_to(Some(dec!(1.00)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_overdraft_epsilon() -> anyhow::Result<()> {
+        let mut test_context = TestEngine {
+            options: Options {
+                deny_withdrawal_overdraft_epsilon: Some(dec!(0.01)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::withdrawal(1, 2, dec!(10.02)), false),
+            (TransactionBuilder::withdrawal(1, 3, dec!(10.01)), true),
+            (TransactionBuilder::withdrawal(1, 4, dec!(0.001)), false),
+        ])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(-0.01));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(-0.01));
+
+        // Without tolerance the withdrawal must be covered
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::withdrawal(1, 2, dec!(10.01)), false),
+            (TransactionBuilder::withdrawal(1, 3, dec!(10)), true),
+        ])?;
+
+        Ok(())
     }
 
-    let data = String::from_utf8(wtr.into_inner().await?)?;
-    println!("{}", data);
+    #[tokio::test]
+    async fn test_overdraft_limit() -> anyhow::Result<()> {
+        let mut test_context = TestEngine {
+            options: Options {
+                deny_withdrawal_overdraft_epsilon: Some(dec!(100)),
+                // Overdrawn totals within the limit hold the invariants
+                verify_invariants: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(50)), true),
+            // Within the limit
+            (TransactionBuilder::withdrawal(1, 2, dec!(80)), true),
+            (TransactionBuilder::withdrawal(1, 3, dec!(70)), true),
+            // Beyond it, even by a fraction
+            (TransactionBuilder::withdrawal(1, 4, dec!(0.01)), false),
+            (TransactionBuilder::deposit(1, 5, dec!(20)), true),
+            (TransactionBuilder::withdrawal(1, 6, dec!(30)), false),
+            (TransactionBuilder::withdrawal(1, 7, dec!(20)), true),
+        ])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)].available).is_equal_to(dec!(-100));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(-100));
 
-    Ok(())
-}
+        Ok(())
+    }
 
-fn parse_single_transaction(
-    transaction: &mut Transaction,
-    clients: &mut ClientHash,
-    past_transactions: &mut TransactionHash,
-    disputed_transactions: &mut TransactionHash,
-) -> anyhow::Result<()> {
-    let client = match clients.get_mut(&transaction.client) {
-        Some(client) => client,
-        None => {
-            let client = Client {
-                id: transaction.client,
+    fn withdrawal_dispute_context() -> TestEngine {
+        TestEngine {
+            options: Options {
+                dispute_withdrawals: true,
                 ..Default::default()
-            };
-            clients.insert(transaction.client, client);
-            clients
-                .get_mut(&transaction.client)
-                .expect("client isn't available")
+            },
+            ..Default::default()
         }
-    };
+    }
 
-    match transaction.r#type {
-        TransactionType::Deposit => {
-            let amount = transaction.amount.expect("no amount");
-            client.total += amount;
-            client.available += amount;
-            transaction.succeeded = true;
-            past_transactions.insert(transaction.tx, transaction.clone());
-        }
-        TransactionType::Widthdrawal => {
-            let amount = transaction.amount.expect("no amount");
-            if client.available < amount {
-                eprintln!(
-                    "Can't widthdraw amount {} for client {}, not enough fund",
-                    amount, client.id
-                );
-            } else {
-                client.available -= amount;
-                client.total -= amount;
-                transaction.succeeded = true;
-                past_transactions.insert(transaction.tx, transaction.clone());
-            }
+    #[tokio::test]
+    async fn test_dispute_withdrawal_rejected_by_default() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::withdrawal(1, 2, dec!(4)), true),
+            (TransactionBuilder::dispute(1, 2), false),
+        ])?;
+        assert_that!(test_context.clients[&(ClientId(1), None)].held).is_equal_to(dec!(0));
+        assert_that!(test_context.clients[&(ClientId(1), None)].total).is_equal_to(dec!(6));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispute_withdrawal_rejection_reason() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::withdrawal(1, 2, dec!(4)), true),
+        ])?;
+
+        for (tx, reason) in [(2, Reason::WithdrawalNotDisputable), (3, Reason::UnknownTx)] {
+            let outcome = test_context.dispute(1, tx)?;
+            assert!(!outcome.is_applied());
+            assert_that!(outcome.reason()).is_equal_to(Some(reason));
         }
-        TransactionType::Dispute => match past_transactions.get(&transaction.tx) {
-            None => {
-                eprintln!(
-                    "Can't dispute tx {} for client {}, non-existing transaction",
-                    transaction.tx, client.id
-                );
-            }
-            Some(past_transaction) => {
-                if past_transaction.r#type == TransactionType::Deposit {
-                    let amount = past_transaction
-                        .amount
-                        .expect("no amount for past transaction");
-
-                    if client.available < amount {
-                        eprintln!(
-                            "Can't dispute amount {} for client {}, not enough fund",
-                            amount, client.id
-                        );
-                    } else {
-                        client.held += amount;
-                        client.available -= amount;
-                        disputed_transactions.insert(past_transaction.tx, past_transaction.clone());
-                        transaction.succeeded = true
-                    }
-                } else {
-                    eprintln!(
-                        "Can't dispute tx {} for client {}, isn't a deposit tx",
-                        past_transaction.tx, client.id
-                    );
-                }
-            }
-        },
-        TransactionType::Resolve => match disputed_transactions.get(&transaction.tx) {
-            None => {
-                eprintln!(
-                    "Can't resolve tx {} for client {}, non-existing disputed transaction",
-                    transaction.tx, client.id
-                );
-            }
-            Some(disputed_transaction) => {
-                let amount = disputed_transaction
-                    .amount
-                    .expect("no amount for disputed transaction");
-
-                client.held -= amount;
-                client.available += amount;
-                disputed_transactions.remove(&transaction.tx);
-                transaction.succeeded = true
-            }
-        },
-        TransactionType::Chargeback => match disputed_transactions.get(&transaction.tx) {
-            None => {
-                eprintln!(
-                    "Can't chargeback tx {} for client {}, non-existing disputed transaction",
-                    transaction.tx, client.id
-                );
-            }
-            Some(disputed_transaction) => {
-                let amount = disputed_transaction
-                    .amount
-                    .expect("no amount for disputed transaction");
-
-                client.held -= amount;
-                client.total -= amount;
-                client.locked = true;
-                disputed_transactions.remove(&transaction.tx);
-                transaction.succeeded = true
-            }
-        },
+
+        Ok(())
     }
 
-    eprintln!("Transaction: {:?}", transaction);
-    eprintln!("Client: {:?}", client);
-    Ok(())
-}
+    #[tokio::test]
+    async fn test_dispute_withdrawal_chargeback() -> anyhow::Result<()> {
+        let mut test_context = withdrawal_dispute_context();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::withdrawal(1, 2, dec!(4)), true),
+            (TransactionBuilder::dispute(1, 2), true),
+        ])?;
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert_that!(client.available).is_equal_to(dec!(6));
+        assert_that!(client.held).is_equal_to(dec!(4));
+        assert_that!(client.total).is_equal_to(dec!(10));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use assertor::*;
-    use rust_decimal_macros::dec;
+        test_context.apply_steps([(TransactionBuilder::chargeback(1, 2), true)])?;
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert_that!(client.available).is_equal_to(dec!(10));
+        assert_that!(client.held).is_equal_to(dec!(0));
+        assert_that!(client.total).is_equal_to(dec!(10));
+        assert!(client.locked);
 
-    #[derive(Default)]
-    struct TestContext {
-        clients: ClientHash,
-        past_transactions: TransactionHash,
-        disputed_transactions: TransactionHash,
+        Ok(())
     }
 
     #[tokio::test]
-    async fn test_deposits_one() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(2.0)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+    async fn test_dispute_withdrawal_near_max() -> anyhow::Result<()> {
+        let mut test_context = withdrawal_dispute_context();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, Decimal::MAX), true),
+            (TransactionBuilder::withdrawal(1, 2, dec!(1)), true),
+            (TransactionBuilder::deposit(1, 3, dec!(1)), true),
+        ])?;
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(2.0));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(2.0));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(1);
+        // Holding back the withdrawn amount would raise the total over the maximum
+        let outcome = test_context.dispute(1, 2)?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::Overflow));
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert_that!((client.available, client.held, client.total)).is_equal_to((
+            Decimal::MAX,
+            dec!(0),
+            Decimal::MAX,
+        ));
         assert_that!(test_context.disputed_transactions).has_length(0);
+
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_deposits_two() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(2.0)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+    async fn test_dispute_withdrawal_resolve() -> anyhow::Result<()> {
+        let mut test_context = withdrawal_dispute_context();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::withdrawal(1, 2, dec!(4)), true),
+            (TransactionBuilder::dispute(1, 2), true),
+            (TransactionBuilder::resolve(1, 2), true),
+        ])?;
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert_that!(client.available).is_equal_to(dec!(6));
+        assert_that!(client.held).is_equal_to(dec!(0));
+        assert_that!(client.total).is_equal_to(dec!(6));
+        assert!(!client.locked);
+
+        Ok(())
+    }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(5.890)),
+    fn deposit_clients(options: Options, clients: u16) -> anyhow::Result<TestEngine> {
+        let mut test_context = TestEngine {
+            options,
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        for client in 1..=clients {
+            test_context.deposit(client, client as u32, dec!(1))?;
+        }
+        Ok(test_context)
+    }
+
+    #[tokio::test]
+    async fn test_limit_clients_under() -> anyhow::Result<()> {
+        let options = Options {
+            limit_clients: Some(3),
+            ..Default::default()
+        };
+        let test_context = deposit_clients(options, 3)?;
+        assert_that!(test_context.clients.len()).is_equal_to(3);
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(7.890));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(7.890));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(0);
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_widthdrawal_enough_fund() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
-
-        let mut transaction = Transaction {
-            r#type: TransactionType::Widthdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(10.001)),
+    async fn test_limit_clients_exceeded() -> anyhow::Result<()> {
+        let options = Options {
+            limit_clients: Some(2),
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
-
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(10.1224));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(10.1224));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(0);
+        let error = deposit_clients(options, 3).err().unwrap();
+        assert_that!(error.to_string()).is_equal_to(
+            "Too many clients, tx 3 would create client 3 over --limit-clients 2".to_string(),
+        );
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_widthdrawal_not_enough_fund() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
-
-        let mut transaction = Transaction {
-            r#type: TransactionType::Widthdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(20.12345)),
+    async fn test_amount_unit_cents() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1050
+widthdrawal,1,2,25
+deposit,1,3,1.5
+";
+        let options = Options {
+            amount_unit: AmountUnit::Cents,
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(!transaction.succeeded);
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].available).is_equal_to(dec!(10.25));
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(20.1234));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(20.1234));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(1);
-        assert_that!(test_context.disputed_transactions).has_length(0);
+        let output = String::from_utf8(write_clients(&clients, &options, vec![]).await?)?;
+        assert_that!(output)
+            .is_equal_to("client,available,held,total,locked\n1,10.25,0,10.25,false\n".to_string());
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_dispute_tx_exists() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+    async fn test_dispute_on_locked_account() -> anyhow::Result<()> {
+        let mut test_context = TestEngine::default();
+        test_context.apply_steps([
+            (TransactionBuilder::deposit(1, 1, dec!(10)), true),
+            (TransactionBuilder::deposit(1, 2, dec!(5)), true),
+            (TransactionBuilder::dispute(1, 1), true),
+            (TransactionBuilder::chargeback(1, 1), true),
+            (TransactionBuilder::dispute(1, 2), false),
+        ])?;
+        let client = &test_context.clients[&(ClientId(1), None)];
+        assert!(client.locked);
+        assert_that!(client.available).is_equal_to(dec!(5));
+        assert_that!(client.held).is_equal_to(dec!(0));
+        assert_that!(test_context.disputed_transactions).has_length(0);
+
+        Ok(())
+    }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(1.123)),
+    #[tokio::test]
+    async fn test_merge_sorted_inputs() -> anyhow::Result<()> {
+        let first: &[u8] = b"type,client,tx,amount,timestamp
+deposit,1,1,10.0,1
+dispute,1,1,,4
+widthdrawal,1,3,2.0,6
+resolve,1,1,,6
+";
+        let second: &[u8] = b"type,client,tx,amount,timestamp
+widthdrawal,1,2,9.0,2
+deposit,2,4,1.0,5
+chargeback,1,1,,6
+deposit,2,5,3.0,9
+";
+        let merged: &[u8] = b"type,client,tx,amount,timestamp
+deposit,1,1,10.0,1
+widthdrawal,1,2,9.0,2
+dispute,1,1,,4
+deposit,2,4,1.0,5
+widthdrawal,1,3,2.0,6
+resolve,1,1,,6
+chargeback,1,1,,6
+deposit,2,5,3.0,9
+";
+        let options = Options {
+            trace_state: true,
+            account_policy: AccountPolicy::AllowNegative,
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client: 1,
-            tx: 2,
+        let mut merged_trace = vec![];
+        let merged_clients =
+            parse_merged_into_engine(vec![first, second], &options, &mut merged_trace)
+                .await?
+                .into_clients();
+        let mut single_trace = vec![];
+        let single_clients = parse_transactions(merged, &options, &mut single_trace).await?;
+
+        assert_that!(String::from_utf8(merged_trace)?)
+            .is_equal_to(String::from_utf8(single_trace)?);
+        assert_that!(merged_clients).is_equal_to(single_clients);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_file_sync() -> anyhow::Result<()> {
+        let input = std::env::temp_dir().join(format!("ex-sync-{}.csv", std::process::id()));
+        let output = std::env::temp_dir().join(format!("ex-sync-{}-out.csv", std::process::id()));
+        std::fs::write(&input, LOCKED_AND_UNLOCKED)?;
+        let options = Options {
+            input: Some(input.display().to_string()),
+            output: OutputSink::File(output.clone()),
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        parse_file_sync(&options)?;
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(20.1234));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(1.123));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(1);
+        let written = std::fs::read_to_string(&output)?;
+        std::fs::remove_file(&input)?;
+        std::fs::remove_file(&output)?;
+        assert_that!(written.lines().collect::<Vec<_>>()).is_equal_to(vec![
+            "client,available,held,total,locked",
+            "1,1.5,0,1.5,false",
+            "2,0,0,0,true",
+        ]);
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_dispute_tx_does_not_exist() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
+    async fn test_all_or_nothing() -> anyhow::Result<()> {
+        let input = std::env::temp_dir().join(format!("ex-atomic-{}.csv", std::process::id()));
+        let output = std::env::temp_dir().join(format!("ex-atomic-{}-out.csv", std::process::id()));
+        std::fs::write(
+            &input,
+            b"type,client,tx,amount
+deposit,1,1,5.0
+widthdrawal,1,2,8.0
+deposit,2,3,abc
+deposit,2,4,1.0
+",
         )?;
-        assert!(transaction.succeeded);
-
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(1.123)),
+        let options = Options {
+            input: Some(input.display().to_string()),
+            output: OutputSink::File(output.clone()),
+            all_or_nothing: true,
+            log_format: LogFormat::Json,
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        let error = parse_data(&options).await.unwrap_err().to_string();
+        std::fs::remove_file(&input)?;
+
+        assert!(!output.exists());
+        let lines = error.lines().collect::<Vec<_>>();
+        assert_that!(lines).has_length(3);
+        assert_that!(lines[0])
+            .is_equal_to("2 rows failed with --all-or-nothing, no output written:");
+        assert_that!(lines[1]).is_equal_to("row 2: tx 2 rejected, insufficient_funds");
+        assert!(lines[2].starts_with("row 3: invalid, "));
+
+        Ok(())
+    }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client: 1,
-            tx: 3,
+    #[tokio::test]
+    async fn test_unwritable_output_fails_before_processing() -> anyhow::Result<()> {
+        let output = std::env::temp_dir().join("ex-missing-dir").join("out.csv");
+        let options = Options {
+            // Would fail too, but only once the output is checked
+            input: Some("missing-input.csv".to_string()),
+            output: OutputSink::File(output.clone()),
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(!transaction.succeeded);
+        let error = parse_data(&options).await.unwrap_err();
+        assert_that!(error.to_string())
+            .is_equal_to(format!("Can't write the output to {}", output.display()));
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_from_snapshot() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,4.0
+dispute,1,1,
+widthdrawal,2,3,1.5
+resolve,1,1,
+dispute,2,2,
+chargeback,2,2,
+";
+        let options = Options::default();
+        let expected = parse_transactions(input, &options, &mut io::sink()).await?;
+        // Disputes and resolves on both sides of the snapshot
+        for row in 1..=7 {
+            let engine = replay_from_snapshot(input, row, &options, &mut io::sink()).await?;
+            assert_that!(engine.into_clients()).is_equal_to(expected.clone());
+        }
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_resolve_tx_exists() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+    async fn test_replay_divergence() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+";
+        let options = Options::default();
+        let expected = parse_into_engine(input, &options, &mut io::sink()).await?;
+        let mut replayed = parse_into_engine(input, &options, &mut io::sink()).await?;
+        compare_replay(1, &expected, &replayed)?;
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(1.123)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        // A snapshot losing the disputed transactions
+        replayed.disputed_transactions.remove(&TxId(1));
+        assert_that!(compare_replay(1, &expected, &replayed)
+            .unwrap_err()
+            .to_string())
+        .is_equal_to(
+            "Replay from the snapshot at row 1 diverges in the transactions history".to_string(),
+        );
+        // Or rounding amounts
+        replayed.clients.get_mut(&(ClientId(1), None)).unwrap().held = dec!(9.99);
+        assert!(compare_replay(1, &expected, &replayed)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Replay from the snapshot at row 1 diverges for client 1: "));
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client: 1,
-            tx: 2,
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        Ok(())
+    }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Resolve,
-            client: 1,
-            tx: 2,
+    #[tokio::test]
+    async fn test_no_headers_with_schema() -> anyhow::Result<()> {
+        let input: &'static [u8] = b"1,1,deposit,10.0
+2,2,deposit,4.0
+3,1,widthdrawal,2.5
+2,2,dispute,
+";
+        let options = Options {
+            no_headers: true,
+            schema: Some("tx, client, type, amount".to_string()),
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        let input = input::with_headers(Box::new(input), &options.schema()?);
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(0);
+        let client = &clients[&(ClientId(1), None)];
+        assert_that!((client.available, client.held, client.total)).is_equal_to((
+            dec!(7.5),
+            dec!(0),
+            dec!(7.5),
+        ));
+        let client = &clients[&(ClientId(2), None)];
+        assert_that!((client.available, client.held, client.total)).is_equal_to((
+            dec!(0),
+            dec!(4),
+            dec!(4),
+        ));
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_resolve_tx_does_not_exist() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+    async fn test_snapshots_per_input() -> anyhow::Result<()> {
+        let first: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+";
+        let second: &[u8] = b"type,client,tx,amount
+widthdrawal,1,3,4.0
+dispute,2,2,
+";
+        let options = Options::default();
+        let snapshots = parse_snapshots(vec![first, second], &options, &mut io::sink()).await?;
+        assert_that!(snapshots).has_length(2);
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(1.123)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        let outputs = [
+            "client,available,held,total,locked
+1,10,0,10,false
+2,5,0,5,false
+",
+            "client,available,held,total,locked
+1,6,0,6,false
+2,0,5,5,false
+",
+        ];
+        for (clients, expected) in snapshots.iter().zip(outputs) {
+            let output = write_clients(clients, &options, vec![]).await?;
+            assert_that!(String::from_utf8(output)?.as_str()).is_equal_to(expected);
+        }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client: 1,
-            tx: 3,
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(!transaction.succeeded);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_path() {
+        assert_that!(snapshot_path(Path::new("out/clients.csv"), 2))
+            .is_equal_to(PathBuf::from("out/clients.2.csv"));
+        assert_that!(snapshot_path(Path::new("clients"), 1))
+            .is_equal_to(PathBuf::from("clients.1"));
+    }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Resolve,
-            client: 1,
-            tx: 3,
+    #[tokio::test]
+    async fn test_explain() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+dispute,1,1,
+dispute,1,2,
+resolve,1,1,
+chargeback,1,1,
+";
+        let options = Options {
+            explain: Some(1),
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(!transaction.succeeded);
+        let mut trace = vec![];
+        parse_transactions(input, &options, &mut trace).await?;
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].locked).is_equal_to(false);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(0);
+        let trace = String::from_utf8(trace)?;
+        assert_that!(trace.lines().collect::<Vec<_>>()).is_equal_to(vec![
+            "Deposit of 5.0 in tx 1: applied, client 1: available=5.0 held=0 total=5.0 locked=false",
+            "Dispute of tx 1: applied, client 1: available=3.0 held=5.0 total=8.0 locked=false",
+            "Resolve of tx 1: applied, client 1: available=5.0 held=3.0 total=8.0 locked=false",
+            "Chargeback of tx 1 for client 1: rejected, not_disputed",
+        ]);
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_resolve_chargeback_exists() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+    async fn test_non_ascii_currency_round_trips() -> anyhow::Result<()> {
+        let input = "type,client,tx,amount,currency
+deposit,1,1,10.0,円
+deposit,1,2,2.5,€uro
+"
+        .as_bytes();
+        let clients = parse_transactions(input, &Options::default(), &mut io::sink()).await?;
+        let output = write_clients(&clients, &Options::default(), vec![]).await?;
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(1.123)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        assert_that!(output).is_equal_to(
+            "client,available,held,total,locked,currency
+1,2.5,0,2.5,false,€uro
+1,10,0,10,false,円
+"
+            .as_bytes()
+            .to_vec(),
+        );
+
+        Ok(())
+    }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client: 1,
-            tx: 2,
+    #[tokio::test]
+    async fn test_gross_totals() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,5.5
+widthdrawal,1,3,4.0
+widthdrawal,1,4,100.0
+dispute,1,2,
+chargeback,1,2,
+deposit,2,5,1.0
+";
+        let options = Options {
+            gross_totals: true,
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        let clients = parse_transactions(input, &options, &mut io::sink()).await?;
+        assert_that!(clients[&(ClientId(1), None)].gross_deposits).is_equal_to(dec!(15.5));
+        assert_that!(clients[&(ClientId(1), None)].gross_withdrawals).is_equal_to(dec!(4));
+
+        let output = String::from_utf8(write_clients(&clients, &options, vec![]).await?)?;
+        assert_that!(output).is_equal_to(
+            "client,available,held,total,locked,gross_deposits,gross_withdrawals,net_deposits
+1,6,0,6,true,15.5,4,11.5
+2,1,0,1,false,1,0,1
+"
+            .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gross_totals_saturate() -> anyhow::Result<()> {
+        let max = Decimal::MAX.to_string();
+        let mut engine = Engine::new(Options::default());
+        for (tx, r#type, amount) in [
+            ("1", "deposit", max.as_str()),
+            ("2", "widthdrawal", max.as_str()),
+            ("3", "deposit", max.as_str()),
+            ("4", "widthdrawal", "1"),
+        ] {
+            let (_, outcome) =
+                engine.apply_record(&ByteRecord::from(vec![r#type, "1", tx, amount]))?;
+            assert_that!(outcome).is_equal_to(Outcome::Applied);
+        }
+
+        let client = &engine.clients()[&(ClientId(1), None)];
+        assert_that!(client.gross_deposits).is_equal_to(Decimal::MAX);
+        assert_that!(client.gross_withdrawals).is_equal_to(Decimal::MAX);
+        assert_that!(client.gross_fields()[2].clone()).is_equal_to("0".to_string());
+
+        Ok(())
+    }
+
+    /// Trace sink interrupting the run once it got `after` snapshots
+    struct InterruptAfter {
+        interrupt: Arc<AtomicBool>,
+        after: usize,
+    }
+
+    impl Write for InterruptAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.ends_with(b"\n") {
+                self.after = self.after.saturating_sub(1);
+                if self.after == 0 {
+                    self.interrupt.store(true, Ordering::Relaxed);
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_keeps_partial_state() -> anyhow::Result<()> {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for tx in 1..=3000 {
+            input.push_str(&format!("deposit,{},{},{}\n", tx, tx, tx));
+        }
+        for read_ahead in [0, 2] {
+            let options = Options {
+                trace_state: true,
+                read_ahead,
+                ..Default::default()
+            };
+            let mut trace = InterruptAfter {
+                interrupt: options.interrupt.clone(),
+                after: 3,
+            };
+            let clients = parse_transactions(input.as_bytes(), &options, &mut trace).await?;
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Chargeback,
-            client: 1,
-            tx: 2,
+            let output = String::from_utf8(write_clients(&clients, &options, vec![]).await?)?;
+            assert_that!(output).is_equal_to(
+                "client,available,held,total,locked\n1,1,0,1,false\n2,2,0,2,false\n3,3,0,3,false\n"
+                    .to_string(),
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_before_start() -> anyhow::Result<()> {
+        let options = Options::default();
+        options.interrupt.store(true, Ordering::Relaxed);
+        let clients = parse_transactions(LOCKED_AND_UNLOCKED, &options, &mut io::sink()).await?;
+
+        assert_that!(clients.len()).is_equal_to(0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_log_format() -> anyhow::Result<()> {
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,1.0
+widthdrawal,1,2,5.0
+deposit,1,x,1.0
+dispute,1,2,
+";
+        let options = Options {
+            log_format: LogFormat::Json,
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        let mut trace = vec![];
+        parse_transactions(input, &options, &mut trace).await?;
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(20.1234));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(20.1234));
-        assert!(test_context.clients[&1].locked);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(0);
+        let lines = String::from_utf8(trace)?
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_that!(lines.len()).is_equal_to(3);
+        assert_that!(lines[0].clone()).is_equal_to(serde_json::json!({
+            "row": 2, "tx": 2, "client": 1, "reason": "insufficient_funds"
+        }));
+        assert_that!(lines[1]["row"].clone()).is_equal_to(serde_json::json!(3));
+        assert_that!(lines[1]["reason"].clone()).is_equal_to(serde_json::json!("invalid_row"));
+        assert!(lines[1]["error"].is_string());
+        assert_that!(lines[2].clone()).is_equal_to(serde_json::json!({
+            "row": 4, "tx": 2, "client": 1, "reason": "unknown_tx"
+        }));
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_resolve_chargeback_does_not_exist() -> anyhow::Result<()> {
-        let mut test_context = TestContext::default();
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 1,
-            amount: Some(dec!(20.1234)),
-            ..Default::default()
-        };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+    async fn test_sort_by_total_ties_ordered_by_id() -> anyhow::Result<()> {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for client in (1..=50).rev() {
+            let amount = if client % 10 == 0 { "1.0" } else { "2.0" };
+            input.push_str(&format!("deposit,{},{},{}\n", client, client, amount));
+        }
+        let clients =
+            parse_transactions(input.as_bytes(), &Options::default(), &mut io::sink()).await?;
+
+        for sort_by in [SortBy::Total, SortBy::Available] {
+            let options = Options {
+                sort_by,
+                ..Default::default()
+            };
+            let output = String::from_utf8(write_clients(&clients, &options, vec![]).await?)?;
+            let ids = output
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap_or_default().to_string())
+                .collect::<Vec<_>>();
+            let expected = (1..=50)
+                .filter(|client| client % 10 == 0)
+                .chain((1..=50).filter(|client| client % 10 != 0))
+                .map(|client| client.to_string())
+                .collect::<Vec<_>>();
+            assert_that!(ids).is_equal_to(expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_row_range() -> anyhow::Result<()> {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for tx in 1..=30 {
+            input.push_str(&format!("deposit,{},{},1.0\n", tx, tx));
+        }
+        input.push_str("dispute,5,5,\ndispute,15,15,\n");
+
+        for read_ahead in [0, 2] {
+            let options = Options {
+                begin: Some(10),
+                end: Some(20),
+                read_ahead,
+                ..Default::default()
+            };
+            let clients = parse_transactions(input.as_bytes(), &options, &mut io::sink()).await?;
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(1.123)),
+            let mut ids = clients.keys().map(|(id, _)| id.0).collect::<Vec<_>>();
+            ids.sort_unstable();
+            assert_that!(ids).is_equal_to((10..=20).collect::<Vec<_>>());
+        }
+
+        let options = Options {
+            begin: Some(20),
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(transaction.succeeded);
+        let clients = parse_transactions(input.as_bytes(), &options, &mut io::sink()).await?;
+        assert_that!(clients.len()).is_equal_to(13);
+        // The disputed deposit was skipped
+        assert_that!(clients[&(ClientId(5), None)].held).is_equal_to(dec!(0));
+        assert_that!(clients[&(ClientId(15), None)].held).is_equal_to(dec!(0));
+
+        Ok(())
+    }
 
-        let mut transaction = Transaction {
-            r#type: TransactionType::Resolve,
-            client: 1,
-            tx: 3,
+    #[tokio::test]
+    async fn test_notices_logged_as_json() -> anyhow::Result<()> {
+        let input = b"type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+dispute,1,2,
+";
+        let options = Options {
+            begin: Some(2),
+            log_format: LogFormat::Json,
             ..Default::default()
         };
-        parse_single_transaction(
-            &mut transaction,
-            &mut test_context.clients,
-            &mut test_context.past_transactions,
-            &mut test_context.disputed_transactions,
-        )?;
-        assert!(!transaction.succeeded);
+        let mut trace = vec![];
+        parse_transactions(&input[..], &options, &mut trace).await?;
 
-        assert_that!(test_context.clients[&1].available).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert_that!(test_context.clients[&1].held).is_equal_to(dec!(0));
-        assert_that!(test_context.clients[&1].total).is_equal_to(dec!(20.1234) + dec!(1.123));
-        assert!(!test_context.clients[&1].locked);
-        assert_that!(test_context.clients).has_length(1);
-        assert_that!(test_context.past_transactions).has_length(2);
-        assert_that!(test_context.disputed_transactions).has_length(0);
+        assert_that!(String::from_utf8(trace)?).is_equal_to(
+            r#"{"row":null,"tx":null,"client":null,"reason":"skipped_rows","message":"Skipping rows before 2, disputes of their transactions will be rejected"}
+{"row":null,"tx":null,"client":null,"reason":"open_disputes","message":"1 disputes still open at the end of the input, their funds stay held"}
+"#
+            .to_string(),
+        );
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_row_range_end_before_begin() {
+        let options = Options {
+            begin: Some(10),
+            end: Some(5),
+            ..Default::default()
+        };
+        assert!(
+            parse_transactions(LOCKED_AND_UNLOCKED, &options, &mut io::sink())
+                .await
+                .is_err()
+        );
+    }
 }
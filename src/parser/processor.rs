@@ -0,0 +1,432 @@
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+
+use super::{ClientHash, Engine, Notice, NoticeReason};
+use crate::entities::id::{ClientId, TxId};
+use crate::entities::transaction::{Outcome, Reason, Transaction, TransactionType};
+use crate::options::{AmountUnit, LogFormat, OpenDisputes, Options, SortedTx};
+
+/// A row of the audit log, fields are empty for rows which couldn't be deserialized
+#[derive(Serialize)]
+struct AuditRow<'a> {
+    r#type: Option<&'a TransactionType>,
+    client: Option<ClientId>,
+    tx: Option<TxId>,
+    amount: Option<Decimal>,
+    succeeded: bool,
+    reason: Option<Reason>,
+}
+
+/// A diagnostic written with `--log-format json`, fields are empty for rows which couldn't be
+/// deserialized
+#[derive(Serialize)]
+struct LogLine {
+    /// 1-based index of the row in the input, headers excluded
+    row: usize,
+    tx: Option<TxId>,
+    client: Option<ClientId>,
+    reason: Reason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A warning written with `--log-format json` when a dispute takes the funds held by a client
+/// over `--held-threshold`
+#[derive(Serialize)]
+struct HeldAlert {
+    row: usize,
+    tx: TxId,
+    client: ClientId,
+    held: Decimal,
+}
+
+/// A client reported with `--log-format json` by `--no-negative-available` once all rows are
+/// applied
+#[derive(Serialize)]
+struct NegativeAvailable {
+    client: ClientId,
+    available: Decimal,
+}
+
+/// Feeds the engine with the rows of a single run, taking care of what surrounds applying
+/// a transaction: tracing, audit log and invariants
+pub(super) struct Processor<'a, T> {
+    engine: Engine,
+    options: &'a Options,
+    trace: &'a mut T,
+    audit: Option<csv::Writer<File>>,
+    /// Rows processed so far
+    rows: usize,
+    /// Rejected or invalid rows, collected with `--all-or-nothing`
+    failures: Vec<String>,
+    /// Last tx id seen by `--require-sorted-tx`, by client or under `None` over the whole input
+    last_tx: HashMap<Option<ClientId>, TxId>,
+}
+
+impl<'a, T: Write> Processor<'a, T> {
+    pub fn new(options: &'a Options, trace: &'a mut T) -> anyhow::Result<Self> {
+        if let Some(begin) = options.begin {
+            if options.end.is_some_and(|end| end < begin) {
+                bail!("--end must not be before --begin");
+            }
+        }
+        let mut engine = Engine::new(options.clone());
+        if let Some(path) = &options.initial_state {
+            engine.load_clients(File::open(path)?)?;
+        }
+        if let Some(path) = &options.restore_maps {
+            engine.load_maps(File::open(path)?)?;
+        }
+        let mut processor = Self::with_engine(options, trace, engine)?;
+        if let Some(begin) = options.begin.filter(|begin| *begin > 1) {
+            processor.notice(Notice::new(
+                NoticeReason::SkippedRows,
+                format!(
+                    "Skipping rows before {}, disputes of their transactions will be rejected",
+                    begin
+                ),
+            ))?;
+        }
+        Ok(processor)
+    }
+
+    /// Same as [`Processor::new`], applying the rows to an existing `engine`
+    pub fn with_engine(
+        options: &'a Options,
+        trace: &'a mut T,
+        mut engine: Engine,
+    ) -> anyhow::Result<Self> {
+        let audit = match &options.audit_log {
+            Some(path) => Some(csv::Writer::from_path(path)?),
+            None => None,
+        };
+        engine.collect_notices = options.log_format == LogFormat::Json;
+        Ok(Processor {
+            engine,
+            options,
+            trace,
+            audit,
+            rows: 0,
+            failures: vec![],
+            last_tx: HashMap::new(),
+        })
+    }
+
+    /// Applies a single deserialized transaction
+    pub fn apply(&mut self, mut transaction: Transaction) -> anyhow::Result<()> {
+        if let Err(e) = transaction.check_schema(self.rows + 1, &self.options.handlers) {
+            return self.skip(e);
+        }
+        if self.options.amount_unit == AmountUnit::Cents {
+            if let Err(e) = transaction.amount_from_cents() {
+                return self.skip(e);
+            }
+        }
+        self.rows += 1;
+        if !self.in_range() || self.already_applied(&transaction) {
+            return Ok(());
+        }
+        self.check_sorted(&transaction)?;
+        let outcome = self.engine.apply(&transaction)?;
+        for notice in std::mem::take(&mut self.engine.notices) {
+            self.notice(Notice {
+                row: Some(self.rows),
+                ..notice
+            })?;
+        }
+
+        if let (true, Some(reason)) = (self.options.all_or_nothing, outcome.reason()) {
+            self.failures.push(format!(
+                "row {}: tx {} rejected, {}",
+                self.rows, transaction.tx, reason
+            ));
+        }
+
+        if let (LogFormat::Json, Some(reason)) = (self.options.log_format, outcome.reason()) {
+            self.log(LogLine {
+                row: self.rows,
+                tx: Some(transaction.tx),
+                client: Some(transaction.client),
+                reason,
+                error: None,
+            })?;
+        }
+
+        if transaction.r#type == TransactionType::Dispute && outcome.is_applied() {
+            self.check_held(&transaction)?;
+        }
+
+        if self.options.trace_state && outcome.is_applied() {
+            if let Some(client) = self.engine.client(&transaction) {
+                serde_json::to_writer(&mut *self.trace, &client)?;
+                writeln!(self.trace)?;
+            }
+        }
+
+        if self.options.explain == Some(transaction.tx.0) {
+            let explanation = self.explain(&transaction, &outcome);
+            writeln!(self.trace, "{}", explanation)?;
+        }
+
+        if let Some(audit) = &mut self.audit {
+            audit.serialize(AuditRow {
+                r#type: Some(&transaction.r#type),
+                client: Some(transaction.client),
+                tx: Some(transaction.tx),
+                amount: transaction.amount,
+                succeeded: outcome.is_applied(),
+                reason: outcome.reason(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails with `--require-sorted-tx` when `transaction` introduces a tx id which isn't greater
+    /// than the previous one. Disputes, resolves and chargebacks refer to earlier ids, they
+    /// aren't checked.
+    fn check_sorted(&mut self, transaction: &Transaction) -> anyhow::Result<()> {
+        let key = match self.options.require_sorted_tx {
+            None => return Ok(()),
+            Some(SortedTx::Global) => None,
+            Some(SortedTx::Client) => Some(transaction.client),
+        };
+        if matches!(
+            transaction.r#type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        ) {
+            return Ok(());
+        }
+        if let Some(last) = self.last_tx.insert(key, transaction.tx) {
+            if transaction.tx <= last {
+                bail!(
+                    "Row {}: tx {} of client {} comes after tx {}, unsorted for --require-sorted-tx",
+                    self.rows,
+                    transaction.tx,
+                    transaction.client,
+                    last
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Warns when the client of the dispute `transaction` holds more than `--held-threshold`,
+    /// failing instead with `--strict-held-threshold`
+    fn check_held(&mut self, transaction: &Transaction) -> anyhow::Result<()> {
+        let threshold = match self.options.held_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let client = match self.engine.client(transaction) {
+            Some(client) if client.held > threshold => client,
+            _ => return Ok(()),
+        };
+
+        if self.options.strict_held_threshold {
+            bail!(
+                "Client {} holds {} after tx {}, over --held-threshold {}",
+                client.id,
+                client.held,
+                transaction.tx,
+                threshold
+            );
+        }
+        match self.options.log_format {
+            LogFormat::Text => writeln!(
+                self.trace,
+                "Warning: client {} holds {} after tx {}, over --held-threshold {}",
+                client.id, client.held, transaction.tx, threshold
+            )?,
+            LogFormat::Json => {
+                serde_json::to_writer(
+                    &mut *self.trace,
+                    &HeldAlert {
+                        row: self.rows,
+                        tx: transaction.tx,
+                        client: client.id,
+                        held: client.held,
+                    },
+                )?;
+                writeln!(self.trace)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes what `transaction` did, with the resulting balances of its client
+    fn explain(&self, transaction: &Transaction, outcome: &Outcome) -> String {
+        let action = match transaction.amount {
+            Some(amount) => format!(
+                "{} of {} in tx {}",
+                transaction.r#type, amount, transaction.tx
+            ),
+            None => format!("{} of tx {}", transaction.r#type, transaction.tx),
+        };
+        match (outcome, self.engine.client(transaction)) {
+            (Outcome::Applied, Some(client)) => format!("{}: applied, {}", action, client),
+            (Outcome::Rejected(reason), _) => format!(
+                "{} for client {}: rejected, {}",
+                action, transaction.client, reason
+            ),
+            _ => format!("{} for client {}: rejected", action, transaction.client),
+        }
+    }
+
+    /// Reports the clients whose available funds went negative, e.g. after the dispute of
+    /// a deposit they had already withdrawn
+    fn reconcile(&mut self) -> anyhow::Result<()> {
+        for client in self.engine.negative_available() {
+            match self.options.log_format {
+                LogFormat::Text => writeln!(
+                    self.trace,
+                    "Warning: client {} has negative available {}",
+                    client.id, client.available
+                )?,
+                LogFormat::Json => {
+                    serde_json::to_writer(
+                        &mut *self.trace,
+                        &NegativeAvailable {
+                            client: client.id,
+                            available: client.available,
+                        },
+                    )?;
+                    writeln!(self.trace)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `notice` on STDERR like other human readable diagnostics, or to the trace along
+    /// with the rejections with `--log-format json`
+    fn notice(&mut self, notice: Notice) -> anyhow::Result<()> {
+        match self.options.log_format {
+            LogFormat::Text => notice.write(self.options, &mut io::stderr()),
+            LogFormat::Json => notice.write(self.options, self.trace),
+        }
+    }
+
+    fn log(&mut self, line: LogLine) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut *self.trace, &line)?;
+        writeln!(self.trace)?;
+        Ok(())
+    }
+
+    /// Whether the run was interrupted or reached `--end`, no more rows should be applied
+    pub fn interrupted(&self) -> bool {
+        self.options.interrupt.load(Ordering::Relaxed)
+            || self.options.end.is_some_and(|end| self.rows >= end)
+    }
+
+    /// Whether `transaction` is from before `--since-tx`, disputes, resolves and chargebacks
+    /// aside as their tx id is the one of the transaction they refer to
+    fn already_applied(&self, transaction: &Transaction) -> bool {
+        let refers = matches!(
+            transaction.r#type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        !refers
+            && self
+                .options
+                .since_tx
+                .is_some_and(|since| transaction.tx.0 < since)
+    }
+
+    /// Whether the current row is within `--begin` and `--end`
+    fn in_range(&self) -> bool {
+        self.options.begin.is_none_or(|begin| self.rows >= begin)
+            && self.options.end.is_none_or(|end| self.rows <= end)
+    }
+
+    /// Skips a row which couldn't be deserialized or holds an invalid amount
+    pub fn skip(&mut self, error: impl Display) -> anyhow::Result<()> {
+        self.rows += 1;
+        if !self.in_range() {
+            return Ok(());
+        }
+        self.engine.stats.skipped += 1;
+        if self.options.all_or_nothing {
+            self.failures
+                .push(format!("row {}: invalid, {}", self.rows, error));
+        }
+        match self.options.log_format {
+            LogFormat::Text => writeln!(self.trace, "Skipping invalid row: {}", error)?,
+            LogFormat::Json => self.log(LogLine {
+                row: self.rows,
+                tx: None,
+                client: None,
+                reason: Reason::InvalidRow,
+                error: Some(error.to_string()),
+            })?,
+        }
+
+        if let Some(audit) = &mut self.audit {
+            audit.serialize(AuditRow {
+                r#type: None,
+                client: None,
+                tx: None,
+                amount: None,
+                succeeded: false,
+                reason: Some(Reason::InvalidRow),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Clients as of the rows processed so far
+    pub fn clients(&self) -> &ClientHash {
+        self.engine.clients()
+    }
+
+    /// Ends the run, returning the engine once all transactions are applied
+    pub fn finish(mut self) -> anyhow::Result<Engine> {
+        if let Some(audit) = &mut self.audit {
+            audit.flush()?;
+        }
+        let open = self.engine.finalize()?;
+        for notice in std::mem::take(&mut self.engine.notices) {
+            self.notice(notice)?;
+        }
+        self.engine.collect_notices = false;
+        if !open.is_empty() {
+            self.notice(Notice::new(
+                NoticeReason::OpenDisputes,
+                format!(
+                    "{} disputes still open at the end of the input{}",
+                    open.len(),
+                    match self.options.open_disputes {
+                        OpenDisputes::Hold => ", their funds stay held",
+                        OpenDisputes::Resolve => " were resolved",
+                    }
+                ),
+            ))?;
+        }
+        if self.options.no_negative_available {
+            self.reconcile()?;
+        }
+        if !self.failures.is_empty() {
+            bail!(
+                "{} rows failed with --all-or-nothing, no output written:\n{}",
+                self.failures.len(),
+                self.failures.join("\n")
+            );
+        }
+
+        if self.options.verify_invariants {
+            self.engine.check_invariants()?;
+        }
+        if let Some(path) = &self.options.dump_maps {
+            self.engine.dump_maps(File::create(path)?)?;
+        }
+
+        Ok(self.engine)
+    }
+}
@@ -0,0 +1,89 @@
+use csv_async::{AsyncDeserializer, ByteRecord};
+use std::collections::VecDeque;
+use std::io::Write;
+use tokio::io::AsyncRead;
+use tokio::task::JoinHandle;
+
+use super::parser::{amount_column, normalize_amount, normalize_type, type_column};
+use super::processor::Processor;
+use crate::entities::transaction::Transaction;
+use crate::options::Options;
+
+/// Number of records deserialized by a single task
+const CHUNK_SIZE: usize = 1024;
+
+type Chunk = JoinHandle<Vec<Result<Transaction, String>>>;
+
+/// Reads raw records and deserializes them by chunks on blocking tasks, keeping up to
+/// `options.read_ahead` chunks in flight. Chunks are awaited in the order they were read so
+/// transactions are applied in their original order, which disputes rely on.
+pub(super) async fn parse_records<R, T>(
+    rdr: &mut AsyncDeserializer<R>,
+    processor: &mut Processor<'_, T>,
+    options: &Options,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    T: Write,
+{
+    let headers = rdr.byte_headers().await?.clone();
+    let column = type_column(&headers, options);
+    let amount = amount_column(&headers, options);
+    let mut pending: VecDeque<Chunk> = VecDeque::new();
+    let mut done = false;
+
+    while !done {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        let mut record = ByteRecord::new();
+        while chunk.len() < CHUNK_SIZE {
+            match rdr.read_byte_record(&mut record).await {
+                Ok(true) => {
+                    if let Some(column) = column {
+                        normalize_type(&mut record, column);
+                    }
+                    if let Some(amount) = amount {
+                        normalize_amount(&mut record, amount, options);
+                    }
+                    chunk.push(Ok(record.clone()))
+                }
+                Ok(false) => {
+                    done = true;
+                    break;
+                }
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                // Kept in the chunk so it's reported in order
+                Err(e) => chunk.push(Err(e)),
+            }
+        }
+
+        if !chunk.is_empty() {
+            let headers = headers.clone();
+            pending.push_back(tokio::task::spawn_blocking(move || {
+                chunk
+                    .iter()
+                    .map(|record| match record {
+                        Ok(record) => record
+                            .deserialize::<Transaction>(Some(&headers))
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    })
+                    .collect()
+            }));
+        }
+
+        while pending.len() > options.read_ahead || (done && !pending.is_empty()) {
+            let transactions = pending.pop_front().expect("no pending chunk").await?;
+            for transaction in transactions {
+                if processor.interrupted() {
+                    return Ok(());
+                }
+                match transaction {
+                    Ok(transaction) => processor.apply(transaction)?,
+                    Err(e) => processor.skip(e)?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
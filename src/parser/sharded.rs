@@ -0,0 +1,280 @@
+//! Sharded pipeline: the rows are applied by several engines running in parallel, then merged.
+//!
+//! Rows are routed by client, each shard owning the clients whose id modulo the number of shards
+//! is its index. Disputes, resolves and chargebacks need the transaction they refer to, which
+//! only goes through when it belongs to the same client: a valid dispute is always routed to
+//! the shard holding its transaction, so the transactions history can stay local to each shard
+//! without any lock. A dispute referring to the transaction of another client still lands on
+//! the shard of its own client, where that transaction is unknown, and is rejected as such, a
+//! serial run rejecting it as a client mismatch.
+//!
+//! Since clients don't interact, the balances are those of a serial run. Tx ids are unique
+//! across clients though: a deposit or withdrawal reusing the tx id of another shard waits for
+//! that shard to reach it, and is rejected as a duplicate if that shard applied it. Settings
+//! spanning clients which can't be checked per shard, `--limit-clients` and
+//! `--require-monotonic`, conflict with `--shards`.
+//!
+//! Shards apply the rows on bare engines, without the processor of a serial run: the options
+//! it handles, e.g. `--all-or-nothing`, `--initial-state` or `--log-format`, conflict with
+//! `--shards`, as do `--read-ahead` and `--batch-size` since rows are read serially here.
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use tokio::io::AsyncRead;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::StreamExt;
+
+use super::parser::create_deserializer;
+use super::Engine;
+use crate::entities::id::TxId;
+use crate::entities::transaction::{Transaction, TransactionType};
+use crate::options::{AmountUnit, Options};
+
+/// Rows queued for a shard ahead of it applying them
+const SHARD_QUEUE: usize = 1024;
+
+/// What a shard is sent by the reader
+enum Row {
+    Apply(Transaction),
+    /// A deposit or withdrawal reusing the tx id of `applied`, applied by another shard
+    Reused {
+        transaction: Transaction,
+        applied: Transaction,
+    },
+    /// Asks for the deposit or withdrawal `tx` if the shard applied it, once the rows sent
+    /// before are applied
+    Applied(TxId, oneshot::Sender<Option<Transaction>>),
+}
+
+/// Same as [`super::parse_chained_into_engine`], applying the rows on `shards` engines in
+/// parallel which are merged once all rows are read. Rows of a client keep their order.
+pub async fn parse_sharded_into_engine<R>(
+    readers: Vec<R>,
+    shards: usize,
+    options: &Options,
+) -> anyhow::Result<Engine>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let shards = shards.max(1);
+    let mut senders = Vec::with_capacity(shards);
+    let mut handles = Vec::with_capacity(shards);
+    for _ in 0..shards {
+        let (sender, mut receiver) = mpsc::channel::<Row>(SHARD_QUEUE);
+        let mut engine = Engine::new(options.clone());
+        senders.push(sender);
+        handles.push(tokio::spawn(async move {
+            while let Some(row) = receiver.recv().await {
+                match row {
+                    Row::Apply(transaction) => {
+                        engine.apply(&transaction)?;
+                    }
+                    Row::Reused {
+                        transaction,
+                        applied,
+                    } => {
+                        // Known for the time of this row, the engine rejects it as a serial
+                        // run would
+                        let tx = applied.tx;
+                        engine.past_transactions.insert(tx, applied);
+                        let outcome = engine.apply(&transaction);
+                        engine.past_transactions.remove(&tx);
+                        outcome?;
+                    }
+                    Row::Applied(tx, reply) => {
+                        let _ = reply.send(engine.transaction(tx));
+                    }
+                }
+            }
+            anyhow::Ok(engine)
+        }));
+    }
+
+    // Shard of the last deposit or withdrawal of each tx id, the one which may have applied it
+    let mut owners = HashMap::<TxId, usize>::new();
+    let mut skipped = 0;
+    let mut rows = 0;
+    'readers: for reader in readers {
+        let mut rdr = create_deserializer(reader, options).await?;
+        let mut transactions = rdr.deserialize::<Transaction>();
+        while let Some(transaction) = transactions.next().await {
+            if options.interrupt.load(Ordering::Relaxed) {
+                break 'readers;
+            }
+            rows += 1;
+            let transaction = transaction.map_err(anyhow::Error::from).and_then(|mut t| {
+                t.check_schema(rows, &options.handlers)?;
+                if options.amount_unit == AmountUnit::Cents {
+                    t.amount_from_cents()?;
+                }
+                Ok(t)
+            });
+            match transaction {
+                Ok(transaction) => {
+                    let shard = usize::from(transaction.client.0) % shards;
+                    let row = match route(&transaction, shard, &mut owners, &senders).await {
+                        Some(Some(applied)) => Row::Reused {
+                            transaction,
+                            applied,
+                        },
+                        Some(None) => Row::Apply(transaction),
+                        None => break 'readers,
+                    };
+                    // A closed shard failed, its error is returned below
+                    if senders[shard].send(row).await.is_err() {
+                        break 'readers;
+                    }
+                }
+                Err(e) => {
+                    skipped += 1;
+                    diagnostic!(options, "Skipping invalid row: {}", e);
+                }
+            }
+        }
+    }
+    drop(senders);
+
+    let mut merged: Option<Engine> = None;
+    for handle in handles {
+        let engine = handle.await??;
+        match &mut merged {
+            Some(merged) => merged.merge(engine)?,
+            None => merged = Some(engine),
+        }
+    }
+    let mut engine = merged.expect("there is at least one shard");
+    engine.stats.skipped += skipped;
+    engine.finalize()?;
+    if options.verify_invariants {
+        engine.check_invariants()?;
+    }
+    Ok(engine)
+}
+
+/// Records `shard` as the owner of the tx id of a deposit or withdrawal, returning the
+/// transaction another shard owning it applied under that id, if any. `None` if that shard
+/// failed.
+async fn route(
+    transaction: &Transaction,
+    shard: usize,
+    owners: &mut HashMap<TxId, usize>,
+    senders: &[mpsc::Sender<Row>],
+) -> Option<Option<Transaction>> {
+    if !matches!(
+        transaction.r#type,
+        TransactionType::Deposit | TransactionType::Widthdrawal
+    ) {
+        return Some(None);
+    }
+    match owners.insert(transaction.tx, shard) {
+        Some(owner) if owner != shard => {
+            let (reply, applied) = oneshot::channel();
+            senders[owner]
+                .send(Row::Applied(transaction.tx, reply))
+                .await
+                .ok()?;
+            let applied = applied.await.ok()?;
+            if applied.is_some() {
+                // The owner keeps the tx id
+                owners.insert(transaction.tx, owner);
+            }
+            Some(applied)
+        }
+        _ => Some(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use rust_decimal_macros::dec;
+    use std::io;
+
+    use crate::entities::id::{ClientId, TxId};
+    use crate::entities::transaction::{Outcome, Reason};
+    use crate::parser::parse_into_engine;
+
+    #[tokio::test]
+    async fn test_cross_shard_dispute() -> anyhow::Result<()> {
+        // Clients 1 and 2 are on different shards
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,2,1,
+dispute,1,1,
+widthdrawal,2,3,1.0
+resolve,1,1,
+dispute,2,2,
+chargeback,2,2,
+deposit,3,4,abc
+";
+        let options = Options::default();
+        let serial = parse_into_engine(input, &options, &mut io::sink()).await?;
+        let sharded = parse_sharded_into_engine(vec![input], 2, &options).await?;
+
+        assert_that!(sharded.clients()).is_equal_to(serial.clients());
+        // Only the reason of client 2 disputing tx 1 of client 1 differs
+        assert_that!(sharded.stats()).is_equal_to(serial.stats());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cross_client_dispute_matches_serial() -> anyhow::Result<()> {
+        // Client 2 could cover the dispute of tx 1, which belongs to client 1
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,20.0
+dispute,2,1,
+resolve,2,1,
+chargeback,2,1,
+";
+        let options = Options::default();
+        let serial = parse_into_engine(input, &options, &mut io::sink()).await?;
+        let sharded = parse_sharded_into_engine(vec![input], 2, &options).await?;
+
+        assert_that!(sharded.clients()).is_equal_to(serial.clients());
+        let client = &serial.clients()[&(ClientId(2), None)];
+        assert_that!(client.available).is_equal_to(dec!(20.0));
+        assert_that!(client.held).is_equal_to(dec!(0));
+        assert!(!client.locked);
+        assert!(!serial.is_disputed(TxId(1)));
+
+        let mut engine = Engine::new(options);
+        engine.apply_record(&csv::ByteRecord::from(vec!["deposit", "1", "1", "10.0"]))?;
+        engine.apply_record(&csv::ByteRecord::from(vec!["deposit", "2", "2", "20.0"]))?;
+        let (_, outcome) =
+            engine.apply_record(&csv::ByteRecord::from(vec!["dispute", "2", "1", ""]))?;
+        assert_that!(outcome).is_equal_to(Outcome::Rejected(Reason::ClientMismatch));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tx_id_reused_across_shards() -> anyhow::Result<()> {
+        // Clients 1 and 2 are on different shards, tx 3 is only applied once its first
+        // withdrawal was rejected
+        let input: &[u8] = b"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,1,5.0
+widthdrawal,1,3,20.0
+deposit,2,3,7.0
+deposit,1,3,1.0
+dispute,2,1,
+dispute,2,3,
+";
+        let options = Options::default();
+        let serial = parse_into_engine(input, &options, &mut io::sink()).await?;
+        let sharded = parse_sharded_into_engine(vec![input], 2, &options).await?;
+
+        assert_that!(sharded.clients()).is_equal_to(serial.clients());
+        assert_that!(sharded.stats()).is_equal_to(serial.stats());
+        assert_that!(sharded.stats().rejected).is_equal_to(4);
+        assert_that!(sharded.transaction(TxId(1)).map(|t| t.client)).is_equal_to(Some(ClientId(1)));
+        assert_that!(sharded.transaction(TxId(3)).map(|t| t.client)).is_equal_to(Some(ClientId(2)));
+        assert!(sharded.is_disputed(TxId(3)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fmt::Write;
+use std::ops::AddAssign;
+
+use crate::entities::id::ClientId;
+use crate::entities::transaction::Outcome;
+use crate::options::ReportFormat;
+
+/// Counts of what happened to the rows of a run
+#[derive(Default, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RunStats {
+    /// Transactions which went through
+    pub applied: usize,
+    /// Transactions which were rejected, e.g. for insufficient funds
+    pub rejected: usize,
+    /// Rows which couldn't be deserialized
+    pub skipped: usize,
+}
+
+impl RunStats {
+    pub fn count(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Applied => self.applied += 1,
+            Outcome::Rejected(_) => self.rejected += 1,
+        }
+    }
+}
+
+impl AddAssign<&RunStats> for RunStats {
+    fn add_assign(&mut self, other: &RunStats) {
+        self.applied += other.applied;
+        self.rejected += other.rejected;
+        self.skipped += other.skipped;
+    }
+}
+
+/// Single line summary of a run, written with `--compact`
+#[derive(Default, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Summary {
+    pub clients: usize,
+    pub locked: usize,
+    /// Funds held by all clients, whatever their currency
+    pub held: Decimal,
+    /// Clients whose available funds are negative, left out when there are none
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub negative_available: Vec<ClientId>,
+    #[serde(flatten)]
+    pub stats: RunStats,
+}
+
+impl Summary {
+    /// Fields of the summary in output order, named as serialized
+    fn fields(&self) -> [(&'static str, String); 7] {
+        let negative_available = self
+            .negative_available
+            .iter()
+            .map(|client| client.0.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        [
+            ("clients", self.clients.to_string()),
+            ("locked", self.locked.to_string()),
+            ("held", self.held.to_string()),
+            ("negative_available", negative_available),
+            ("applied", self.stats.applied.to_string()),
+            ("rejected", self.stats.rejected.to_string()),
+            ("skipped", self.stats.skipped.to_string()),
+        ]
+    }
+
+    /// The summary written in `format`, ending with a newline. Negative available clients are
+    /// listed space separated in the text and CSV formats, the CSV columns being always the same.
+    pub fn report(&self, format: ReportFormat) -> anyhow::Result<String> {
+        let mut report = String::new();
+        match format {
+            ReportFormat::Text => {
+                // Like in JSON, no negative available clients line when there are none
+                for (name, value) in self.fields().iter().filter(|(_, value)| !value.is_empty()) {
+                    writeln!(report, "{}: {}", name, value)?;
+                }
+            }
+            ReportFormat::Json => {
+                report = serde_json::to_string(self)?;
+                report.push('\n');
+            }
+            ReportFormat::Csv => {
+                let fields = self.fields();
+                let mut writer = csv::Writer::from_writer(vec![]);
+                writer.write_record(fields.iter().map(|(name, _)| name))?;
+                writer.write_record(fields.iter().map(|(_, value)| value))?;
+                report = String::from_utf8(writer.into_inner()?)?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assertor::*;
+    use rust_decimal_macros::dec;
+
+    fn summary() -> Summary {
+        Summary {
+            clients: 3,
+            locked: 1,
+            held: dec!(5.5),
+            negative_available: vec![ClientId(2), ClientId(3)],
+            stats: RunStats {
+                applied: 7,
+                rejected: 2,
+                skipped: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn test_report_text() -> anyhow::Result<()> {
+        assert_that!(summary().report(ReportFormat::Text)?).is_equal_to(
+            "clients: 3
+locked: 1
+held: 5.5
+negative_available: 2 3
+applied: 7
+rejected: 2
+skipped: 1
+"
+            .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_json() -> anyhow::Result<()> {
+        assert_that!(summary().report(ReportFormat::Json)?).is_equal_to(
+            r#"{"clients":3,"locked":1,"held":"5.5","negative_available":[2,3],"applied":7,"rejected":2,"skipped":1}
+"#
+            .to_string(),
+        );
+        assert_that!(Summary::default().report(ReportFormat::Json)?).is_equal_to(
+            r#"{"clients":0,"locked":0,"held":"0","applied":0,"rejected":0,"skipped":0}
+"#
+            .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_csv() -> anyhow::Result<()> {
+        assert_that!(summary().report(ReportFormat::Csv)?).is_equal_to(
+            "clients,locked,held,negative_available,applied,rejected,skipped
+3,1,5.5,2 3,7,2,1
+"
+            .to_string(),
+        );
+
+        Ok(())
+    }
+}
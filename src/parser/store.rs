@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Key-value storage backing the engine's clients and transactions history.
+///
+/// Values are read and written by copy so an implementation doesn't have to keep them in memory,
+/// a disk-backed store (e.g. sled) can serialize them on `insert` and load them on `get`.
+pub trait Store<K, V> {
+    fn get(&self, key: &K) -> Option<V>;
+    fn insert(&mut self, key: K, value: V);
+    fn remove(&mut self, key: &K) -> Option<V>;
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// All stored entries, in no particular order
+    fn entries(&self) -> Box<dyn Iterator<Item = (K, V)> + '_>;
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Store<K, V> for HashMap<K, V> {
+    fn get(&self, key: &K) -> Option<V> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        HashMap::insert(self, key, value);
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        HashMap::remove(self, key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        HashMap::contains_key(self, key)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(self.iter().map(|(key, value)| (key.clone(), value.clone())))
+    }
+}
@@ -0,0 +1,63 @@
+//! Golden tests: every directory of `tests/fixtures` holds an `input.csv` which, run through the
+//! engine with the default options, must output `expected.csv`.
+//!
+//! Add a scenario by creating a new directory with its `input.csv`, then run the tests with
+//! `UPDATE_FIXTURES=1` to write its `expected.csv`, and review it.
+
+use assertor::*;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ex::options::Options;
+use ex::parser::{parse_transactions, write_clients};
+
+fn cases() -> anyhow::Result<Vec<PathBuf>> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut cases = fs::read_dir(fixtures)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    cases.retain(|case| case.is_dir());
+    cases.sort();
+    Ok(cases)
+}
+
+async fn run_case(case: &Path) -> anyhow::Result<String> {
+    let input = fs::read(case.join("input.csv"))?;
+    let options = Options::default();
+    let clients = parse_transactions(input.as_slice(), &options, &mut io::sink()).await?;
+    Ok(String::from_utf8(
+        write_clients(&clients, &options, vec![]).await?,
+    )?)
+}
+
+#[tokio::test]
+async fn test_fixtures() -> anyhow::Result<()> {
+    let update = env::var_os("UPDATE_FIXTURES").is_some();
+    let cases = cases()?;
+    assert!(!cases.is_empty());
+
+    let mut failures = vec![];
+    for case in cases {
+        let output = run_case(&case).await?;
+        let expected_path = case.join("expected.csv");
+        if update {
+            fs::write(&expected_path, &output)?;
+            continue;
+        }
+
+        let expected = fs::read_to_string(&expected_path)?;
+        if output != expected {
+            failures.push(format!(
+                "{}\nexpected:\n{}output:\n{}",
+                case.display(),
+                expected,
+                output
+            ));
+        }
+    }
+    assert_that!(failures).is_equal_to(Vec::<String>::new());
+
+    Ok(())
+}